@@ -13,18 +13,46 @@
 //
 
 use async_std::sync::Arc;
-use std::convert::TryFrom;
 use structopt::StructOpt;
 use zenoh::{Properties, Zenoh};
-use zenoh_cdn::client::Client;
-use zenoh_cdn::types::DEFAULT_ROOT;
+use zenoh_cdn::client::{Client, DownloadOptions, ProgressCallback, TransferEvent, UploadOptions};
+use zenoh_cdn::crypto::{EncryptionKey, SigningKey};
+use zenoh_cdn::types::{ClientConfig, Compression, ResourceName, DEFAULT_ROOT};
 
 #[derive(StructOpt, Debug)]
 pub struct UploadKind {
     #[structopt(parse(from_os_str), name = "Absolute path of the file to be shared")]
     filename: std::path::PathBuf,
     #[structopt(name = "Path in zenoh for the file")]
-    resource_path: String,
+    resource_path: ResourceName,
+    #[structopt(long, help = "Time-to-live of the file in seconds")]
+    ttl: Option<u64>,
+    #[structopt(long, help = "Maximum upload rate in bytes per second")]
+    rate_limit: Option<u64>,
+    #[structopt(
+        long,
+        default_value = "none",
+        help = "Chunk compression: none, zstd or lz4"
+    )]
+    compression: Compression,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "File holding the hex encoded encryption key"
+    )]
+    key_file: Option<std::path::PathBuf>,
+    #[structopt(
+        long,
+        default_value = "default",
+        help = "Identifier of the encryption key"
+    )]
+    key_id: String,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "File holding the hex encoded signing key"
+    )]
+    signing_key_file: Option<std::path::PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -32,13 +60,67 @@ pub struct DownloadKind {
     #[structopt(parse(from_os_str), name = "Absolute path of the destination")]
     destination_path: std::path::PathBuf,
     #[structopt(name = "Path in zenoh for the file")]
-    resource_path: String,
+    resource_path: ResourceName,
+    #[structopt(long, help = "Maximum download rate in bytes per second")]
+    rate_limit: Option<u64>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "File holding the hex encoded encryption key"
+    )]
+    key_file: Option<std::path::PathBuf>,
+    #[structopt(
+        long,
+        default_value = "default",
+        help = "Identifier of the encryption key"
+    )]
+    key_id: String,
+    #[structopt(long, help = "Hex encoded public key of a trusted publisher")]
+    trusted_publisher: Vec<String>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "File holding the hex encoded signing key"
+    )]
+    signing_key_file: Option<std::path::PathBuf>,
 }
 
 #[derive(StructOpt, Debug)]
 pub enum ClientCLI {
     Upload(UploadKind),
     Download(DownloadKind),
+    Servers,
+}
+
+fn read_key(key_file: &Option<std::path::PathBuf>, key_id: &str) -> Option<EncryptionKey> {
+    key_file.as_ref().map(|path| {
+        let key = std::fs::read_to_string(path).unwrap();
+        EncryptionKey::from_hex(key_id, &key).unwrap()
+    })
+}
+
+fn read_signing_key(key_file: &Option<std::path::PathBuf>) -> Option<SigningKey> {
+    key_file
+        .as_ref()
+        .map(|path| SigningKey::from_hex(&std::fs::read_to_string(path).unwrap()).unwrap())
+}
+
+fn show_progress(event: &TransferEvent) {
+    match event {
+        TransferEvent::Progress {
+            transferred,
+            total,
+            throughput,
+            eta,
+        } => eprint!(
+            "\r{}/{} bytes, {:.0} KiB/s, ETA {:?}    ",
+            transferred,
+            total,
+            throughput / 1024.0,
+            eta.unwrap_or_default()
+        ),
+        event => log::debug!("{:?}", event),
+    }
 }
 
 #[async_std::main]
@@ -49,30 +131,54 @@ async fn main() {
     log::debug!("Args: {:?}", args);
 
     let zsession = Arc::new(
-        Zenoh::new(
-            Properties::from(String::from(
-                "mode=peer",
-            ))
-            .into(),
-        )
-        .await
-        .unwrap(),
+        Zenoh::new(Properties::from(String::from("mode=peer")).into())
+            .await
+            .unwrap(),
     );
-    let client = Client::new(zsession, Some(DEFAULT_ROOT.to_string()));
+    let config = match &args {
+        ClientCLI::Download(down) => ClientConfig {
+            trusted_publishers: down.trusted_publisher.clone(),
+            ..Default::default()
+        },
+        _ => ClientConfig::default(),
+    };
+    let mut client = Client::with_config(zsession, Some(DEFAULT_ROOT.to_string()), config);
 
     match args {
         ClientCLI::Upload(up) => {
-            let resource_name = zenoh::Path::try_from(up.resource_path).unwrap();
-            let path = client.upload(&up.filename, &resource_name).await.unwrap();
+            let options = UploadOptions {
+                ttl: up.ttl,
+                rate_limit: up.rate_limit,
+                compression: up.compression,
+                encryption: read_key(&up.key_file, &up.key_id),
+                signing_key: read_signing_key(&up.signing_key_file),
+                progress: Some(ProgressCallback::new(show_progress)),
+                ..Default::default()
+            };
+            let path = client
+                .upload_with_options(&up.filename, &up.resource_path, &options)
+                .await
+                .unwrap();
             println!("File uploaded to {:?}", path);
         }
         ClientCLI::Download(down) => {
-            let resource_name = zenoh::Path::try_from(down.resource_path).unwrap();
+            client.identity = read_signing_key(&down.signing_key_file);
+            let options = DownloadOptions {
+                rate_limit: down.rate_limit,
+                keys: read_key(&down.key_file, &down.key_id).into_iter().collect(),
+                progress: Some(ProgressCallback::new(show_progress)),
+                ..Default::default()
+            };
             let path = client
-                .download(&resource_name, &down.destination_path)
+                .download_with_options(&down.resource_path, &down.destination_path, &options)
                 .await
                 .unwrap();
             println!("File downloaded to: {:?}", path);
         }
+        ClientCLI::Servers => {
+            for server in client.servers().await.unwrap() {
+                println!("{:?}", server);
+            }
+        }
     }
 }
@@ -42,7 +42,7 @@ async fn main() {
 
     let _h = server.serve();
 
-    println!("Ready!");
+    println!("Ready! Server id {}", server.id);
 
     let () = std::future::pending().await;
 }
@@ -12,154 +12,1046 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 
-use crate::{FILE_CHUNK_PATH, FILE_METADATA_PATH};
+use crate::crypto::{signed_selector, verify_metadata, EncryptionKey, SigningKey};
+use crate::error::{CdnError, CdnResult};
+use crate::{FILE_CHUNK_PATH, FILE_COMMIT_PATH, FILE_METADATA_PATH, LIST_SERVERS_PATH};
 
-use crate::types::{FileMetadata, DEFAULT_CHUNK_SIZE, DEFAULT_ROOT};
-use crate::utils::{create_destination_file, get_bytes_from_file, write_destination_file};
+use crate::types::{
+    hash_chunk, ClientConfig, Compression, FileMetadata, ResourceName, ServerInfo, StaleChunk,
+    UploadCommit, CHECKSUM_PROPERTY, DEFAULT_CHUNK_SIZE, DEFAULT_DOWNLOAD_ATTEMPTS,
+    DEFAULT_PARALLEL_CHUNKS, DEFAULT_ROOT, SERVER_PROPERTY,
+};
+use crate::utils::{
+    create_destination_file, get_bytes_from_file, remove_file_if_exists, rename_file,
+    write_destination_file, RateLimiter,
+};
 use async_std::fs;
 use async_std::path::PathBuf;
 use async_std::prelude::*;
 use async_std::sync::Arc;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use zenoh::{Path as ZPath, Selector};
-use zenoh::{ZError, ZErrorKind, ZResult, Zenoh};
-use zenoh_util::{zerror, zerror2};
+use zenoh::{Workspace, Zenoh};
 
 pub fn hash(filename: &Path) -> String {
     checksums::hash_file(filename, checksums::Algorithm::MD5)
 }
 
+/// Options applied to a single upload.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Time-to-live of the file on the servers, in seconds.
+    pub ttl: Option<u64>,
+    /// Called with the events of the transfer.
+    pub progress: Option<ProgressCallback>,
+    /// Aborts the upload, the chunks already sent are removed.
+    pub cancel: Option<CancellationToken>,
+    /// Bytes per second of this upload, on top of the limit of the client.
+    pub rate_limit: Option<u64>,
+    /// Codec applied to the chunks, reversed by `Client::download`.
+    pub compression: Compression,
+    /// Encrypts the chunks, only the clients holding the key can read them.
+    pub encryption: Option<EncryptionKey>,
+    /// Signs the metadata, for the servers and clients trusting the key.
+    pub signing_key: Option<SigningKey>,
+}
+
+/// Options applied to a single download.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Called with the events of the transfer.
+    pub progress: Option<ProgressCallback>,
+    /// Aborts the download, the partially written file is removed.
+    pub cancel: Option<CancellationToken>,
+    /// Keys able to decrypt the files, looked up by the key id recorded in
+    /// the metadata.
+    pub keys: Vec<EncryptionKey>,
+    /// Bytes per second of this download, on top of the limit of the client.
+    pub rate_limit: Option<u64>,
+}
+
+/// Cancels the transfers it is given to, from any task.
+///
+/// No new chunk request is issued once cancelled, the transfer returns
+/// `CdnError::Cancelled` after the requests in flight completed.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Event reported during an upload or a download.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    ChunkStarted {
+        chunk: usize,
+    },
+    ChunkCompleted {
+        chunk: usize,
+        bytes: usize,
+    },
+    /// The chunk could not be transferred, even after retrying.
+    ChunkFailed {
+        chunk: usize,
+        error: String,
+    },
+    /// The file changed on the servers, the download starts over.
+    Restarted {
+        attempt: usize,
+    },
+    Progress {
+        transferred: u64,
+        total: u64,
+        /// Bytes per second since the beginning of the transfer.
+        throughput: f64,
+        eta: Option<Duration>,
+    },
+}
+
+/// Callback receiving the events of a transfer, it may be called from
+/// several chunk requests at the same time.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&TransferEvent) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&TransferEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
+
+/// Reads the chunk `chunk` of the file, compresses and encrypts it as
+/// described by `metadata`, also returns its original length.
+async fn encode_chunk(
+    file_path: &Path,
+    metadata: &FileMetadata,
+    options: &UploadOptions,
+    chunk: usize,
+) -> CdnResult<(usize, Vec<u8>)> {
+    let original = get_bytes_from_file(file_path, chunk, metadata.chunk_size).await?;
+    let mut data = metadata.compression.compress(&original)?;
+    if let (Some(key), Some(_)) = (&options.encryption, &metadata.encryption) {
+        data = key.encrypt(metadata, chunk, &data)?;
+    }
+    Ok((original.len(), data))
+}
+
+/// Checks a chunk against the metadata, then reverses its encryption and its
+/// compression, which must restore the length of the chunk in the file.
+fn decode_chunk(
+    metadata: &FileMetadata,
+    key: Option<&EncryptionKey>,
+    chunk: usize,
+    data: &[u8],
+) -> CdnResult<Vec<u8>> {
+    metadata.validate_chunk(chunk, data)?;
+    let decrypted;
+    let data = match (&metadata.encryption, key) {
+        (Some(_), Some(key)) => {
+            decrypted = key.decrypt(metadata, chunk, data)?;
+            &decrypted[..]
+        }
+        _ => data,
+    };
+    let expected = metadata.chunk_len(chunk);
+    let decoded = metadata.compression.decompress(data, expected)?;
+    match decoded.len() == expected {
+        true => Ok(decoded),
+        false => Err(CdnError::Integrity(format!(
+            "Chunk {} of {:?} decodes to {} bytes instead of {}",
+            chunk,
+            metadata.resource_name,
+            decoded.len(),
+            expected
+        ))),
+    }
+}
+
+/// Counts the bytes of a transfer, reports its events, tells whether it
+/// was cancelled and throttles it.
+struct Tracker<'a> {
+    callback: Option<&'a ProgressCallback>,
+    cancel: Option<&'a CancellationToken>,
+    limiter: Option<RateLimiter>,
+    client_limiter: Option<&'a RateLimiter>,
+    total: u64,
+    transferred: AtomicU64,
+    start: Instant,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(
+        callback: Option<&'a ProgressCallback>,
+        cancel: Option<&'a CancellationToken>,
+        rate_limit: Option<u64>,
+        client_limiter: Option<&'a RateLimiter>,
+        total: u64,
+    ) -> Self {
+        Self {
+            callback,
+            cancel,
+            limiter: rate_limit.map(RateLimiter::new),
+            client_limiter,
+            total,
+            transferred: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Waits until `bytes` can be transferred within the rate limits.
+    async fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes).await;
+        }
+        if let Some(limiter) = self.client_limiter {
+            limiter.acquire(bytes).await;
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        match self.cancel {
+            Some(cancel) => cancel.is_cancelled(),
+            None => false,
+        }
+    }
+
+    fn report(&self, event: TransferEvent) {
+        if let Some(callback) = self.callback {
+            (callback.0)(&event);
+        }
+    }
+
+    fn started(&self, chunk: usize) {
+        self.report(TransferEvent::ChunkStarted { chunk });
+    }
+
+    fn completed(&self, chunk: usize, bytes: usize) {
+        let transferred =
+            self.transferred.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        self.report(TransferEvent::ChunkCompleted { chunk, bytes });
+
+        let throughput = transferred as f64 / self.start.elapsed().as_secs_f64().max(1e-6);
+        let eta = match throughput > 0.0 {
+            true => Some(Duration::from_secs_f64(
+                self.total.saturating_sub(transferred) as f64 / throughput,
+            )),
+            false => None,
+        };
+        self.report(TransferEvent::Progress {
+            transferred,
+            total: self.total,
+            throughput,
+            eta,
+        });
+    }
+
+    fn failed(&self, chunk: usize, error: &CdnError) {
+        self.report(TransferEvent::ChunkFailed {
+            chunk,
+            error: error.to_string(),
+        });
+    }
+
+    fn restarted(&self, attempt: usize) {
+        self.report(TransferEvent::Restarted { attempt });
+    }
+}
+
+/// What the client measured of a server.
+#[derive(Debug, Clone, Default)]
+struct ServerStats {
+    latency: Option<Duration>,
+    /// Bytes per second.
+    throughput: Option<f64>,
+    inflight: usize,
+}
+
 #[derive(Clone)]
 pub struct Client {
     pub z: Arc<Zenoh>,
     pub root: String,
+    pub config: ClientConfig,
+    /// Signs the queries, for the servers restricting reads to some
+    /// identities.
+    pub identity: Option<SigningKey>,
+    stats: Arc<Mutex<HashMap<String, ServerStats>>>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Counts a chunk request as in flight on a server until dropped.
+struct Inflight<'a> {
+    stats: &'a Mutex<HashMap<String, ServerStats>>,
+    server: String,
+}
+
+impl Drop for Inflight<'_> {
+    fn drop(&mut self) {
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&self.server) {
+            stats.inflight -= 1;
+        }
+    }
 }
 
 impl Client {
     pub fn new(z: Arc<Zenoh>, root: Option<String>) -> Self {
+        Self::with_config(z, root, ClientConfig::default())
+    }
+
+    pub fn with_config(z: Arc<Zenoh>, root: Option<String>, config: ClientConfig) -> Self {
         Self {
             z,
             root: root.unwrap_or_else(|| String::from(DEFAULT_ROOT)),
+            identity: None,
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            limiter: config
+                .rate_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate))),
+            config,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.config
+            .policy
+            .deadline
+            .map(|deadline| Instant::now() + Duration::from_millis(deadline))
+    }
+
+    /// Runs `request` until it succeeds, with the timeout, retries and
+    /// capped exponential backoff of the retry policy. Only the transient
+    /// errors are retried.
+    async fn with_retries<T, E, F, Fut>(
+        &self,
+        what: &str,
+        deadline: Option<Instant>,
+        mut request: F,
+    ) -> CdnResult<T>
+    where
+        E: Into<CdnError>,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let policy = &self.config.policy;
+        let mut backoff = Duration::from_millis(policy.backoff);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut timeout = Duration::from_millis(policy.request_timeout);
+            if let Some(deadline) = deadline {
+                timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+            }
+
+            let error = match async_std::future::timeout(timeout, request()).await {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(e)) => e.into(),
+                Err(_) => CdnError::Timeout(format!("timed out after {:?}", timeout)),
+            };
+
+            let expired = match deadline {
+                Some(deadline) => Instant::now() + backoff >= deadline,
+                None => false,
+            };
+            if !error.is_transient() {
+                return Err(error.context(what));
+            }
+            if attempt > policy.max_retries || expired {
+                return Err(error.context(&format!("{} failed after {} attempts", what, attempt)));
+            }
+            log::warn!(
+                "{} failed (attempt {}): {}, retrying in {:?}",
+                what,
+                attempt,
+                error,
+                backoff
+            );
+            async_std::task::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_millis(policy.max_backoff));
         }
     }
 
     /// Uploads a file to Zenoh-CDN.
     ///
-    pub async fn upload(&self, file_path: &Path, resource_name: &ZPath) -> ZResult<ZPath> {
+    pub async fn upload(&self, file_path: &Path, resource_name: &ResourceName) -> CdnResult<ZPath> {
+        self.upload_with_options(file_path, resource_name, &UploadOptions::default())
+            .await
+    }
+
+    /// Uploads a file to Zenoh-CDN using the given `options`.
+    ///
+    pub async fn upload_with_options(
+        &self,
+        file_path: &Path,
+        resource_name: &ResourceName,
+        options: &UploadOptions,
+    ) -> CdnResult<ZPath> {
         let filename = match file_path.file_name() {
             Some(name) => Ok(name.to_str().unwrap().to_string()),
-            None => Err(zerror2!(ZErrorKind::Other {
-                descr: format!("The path is not a file path {:?}", file_path)
-            })),
+            None => Err(CdnError::Other(format!(
+                "The path is not a file path {:?}",
+                file_path
+            ))),
         }?;
 
         let checksum = hash(file_path);
         let file_metadata = fs::metadata(file_path).await.map_err(|e| {
-            zerror2!(ZErrorKind::Other {
-                descr: format!("Error when getting file {:?} information {}", file_path, e)
-            })
+            CdnError::Io(format!(
+                "Error when getting file {:?} information {}",
+                file_path, e
+            ))
         })?;
 
         let chunks = (file_metadata.len() as usize) / DEFAULT_CHUNK_SIZE + 1;
 
-        let metadata = FileMetadata {
+        let mut metadata = FileMetadata {
             filename,
             checksum,
             chunk_size: DEFAULT_CHUNK_SIZE,
             chunks,
             resource_name: String::from(resource_name.as_str()),
             size: file_metadata.len(),
+            ttl: options.ttl,
+            generation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            compression: options.compression,
+            encryption: options.encryption.as_ref().map(|key| key.encryption()),
+            digests: Vec::with_capacity(chunks),
+            signature: None,
         };
 
-        let ws = self.z.workspace(None).await?;
-
+        // The chunks are encoded twice, first to record their digests in the
+        // metadata announced before them.
         for i in 0..chunks {
-            let data = get_bytes_from_file(file_path, i, DEFAULT_CHUNK_SIZE).await?;
-            let path = ZPath::try_from(FILE_CHUNK_PATH!(self.root, resource_name, i))?;
-            ws.put(&path, data.into()).await?;
+            let (_, data) = encode_chunk(file_path, &metadata, options, i).await?;
+            metadata.digests.push(hash_chunk(&data));
+        }
+        if metadata.encryption.is_some() {
+            metadata.checksum = metadata.digests_checksum();
+        }
+        if let Some(key) = &options.signing_key {
+            key.sign(&mut metadata)?;
         }
 
+        let ws = self.z.workspace(None).await?;
+        let deadline = self.deadline();
+        let tracker = Tracker::new(
+            options.progress.as_ref(),
+            options.cancel.as_ref(),
+            options.rate_limit,
+            self.limiter.as_deref(),
+            metadata.size,
+        );
+
         let path = ZPath::try_from(FILE_METADATA_PATH!(self.root, resource_name))?;
-        let data = metadata.serialize()?;
+        let value = zenoh::Value::Json(metadata.serialize()?);
+        let what = format!("Upload of metadata of {:?}", resource_name);
+        self.with_retries(&what, deadline, || ws.put(&path, value.clone()))
+            .await?;
+
+        for i in 0..chunks {
+            if tracker.cancelled() {
+                self.abort_upload(&ws, &metadata).await;
+                return Err(CdnError::Cancelled(format!(
+                    "Upload of {:?} cancelled",
+                    resource_name
+                )));
+            }
+            let (len, data) = encode_chunk(file_path, &metadata, options, i).await?;
+            let chunk_path = ZPath::try_from(FILE_CHUNK_PATH!(self.root, resource_name, i))?;
+            let what = format!("Upload of chunk {} of {:?}", i, resource_name);
+            tracker.throttle(data.len()).await;
+            tracker.started(i);
+            match self
+                .with_retries(&what, deadline, || ws.put(&chunk_path, data.clone().into()))
+                .await
+            {
+                Ok(()) => tracker.completed(i, len),
+                Err(e) => {
+                    tracker.failed(i, &e);
+                    return Err(e);
+                }
+            }
+        }
 
-        let value = zenoh::Value::Json(data);
-        ws.put(&path, value).await?;
+        let commit = UploadCommit {
+            resource_name: metadata.resource_name.clone(),
+            checksum: metadata.checksum.clone(),
+            abort: false,
+        };
+        let commit_path = ZPath::try_from(FILE_COMMIT_PATH!(self.root, resource_name))?;
+        let value = zenoh::Value::Json(commit.serialize()?);
+        let what = format!("Commit of {:?}", resource_name);
+        self.with_retries(&what, deadline, || ws.put(&commit_path, value.clone()))
+            .await?;
 
         Ok(path)
     }
 
-    pub async fn download(&self, resource_name: &ZPath, destination: &Path) -> ZResult<PathBuf> {
+    /// Drops an upload that did not complete, the servers remove its
+    /// metadata and chunks and keep serving the previous generation.
+    async fn abort_upload(&self, ws: &Workspace<'_>, metadata: &FileMetadata) {
+        let commit = UploadCommit {
+            resource_name: metadata.resource_name.clone(),
+            checksum: metadata.checksum.clone(),
+            abort: true,
+        };
+        let result: CdnResult<()> = async {
+            let path = ZPath::try_from(FILE_COMMIT_PATH!(self.root, metadata.resource_name))?;
+            let value = zenoh::Value::Json(commit.serialize()?);
+            Ok(ws.put(&path, value).await?)
+        }
+        .await;
+        if let Err(e) = result {
+            log::warn!(
+                "Unable to abort the upload of {:?}: {}",
+                metadata.resource_name,
+                e
+            );
+        }
+    }
+
+    /// Lists the storage servers currently announcing themselves.
+    ///
+    pub async fn servers(&self) -> CdnResult<Vec<ServerInfo>> {
         let ws = self.z.workspace(None).await?;
-        let selector = Selector::try_from(FILE_METADATA_PATH!(self.root, resource_name))?;
-        let metadata = {
-            let ds = ws.get(&selector).await?;
-
-            // Not sure this is needed...
-            let data = ds.collect::<Vec<zenoh::Data>>().await;
-
-            match data.len() {
-                0 => zerror!(ZErrorKind::Other {
-                    descr: format!("File not found {:?}", resource_name)
-                }),
-                1 => {
-                    let kv = &data[0];
-                    match &kv.value {
-                        zenoh::Value::Json(value) => Ok(FileMetadata::deserialize(value)?),
-                        _ => zerror!(ZErrorKind::Other {
-                            descr: format!(
-                                "Metadata is not correctly formatted {:?} - {:?}",
-                                resource_name, kv
-                            )
-                        }),
+        let selector = Selector::try_from(LIST_SERVERS_PATH!(self.root))?;
+        let data = ws.get(&selector).await?.collect::<Vec<zenoh::Data>>().await;
+
+        let mut servers = Vec::new();
+        for kv in data {
+            match &kv.value {
+                zenoh::Value::Json(value) => servers.push(ServerInfo::deserialize(value)?),
+                _ => log::warn!("Server information is not correctly formatted {:?}", kv),
+            }
+        }
+        Ok(servers)
+    }
+
+    /// Downloads a file from Zenoh-CDN.
+    ///
+    /// Chunks are requested for the checksum read from the metadata, if the
+    /// file is overwritten during the transfer the download restarts from the
+    /// new metadata, up to `DEFAULT_DOWNLOAD_ATTEMPTS` times.
+    pub async fn download(
+        &self,
+        resource_name: &ResourceName,
+        destination: &Path,
+    ) -> CdnResult<PathBuf> {
+        self.download_with_options(resource_name, destination, &DownloadOptions::default())
+            .await
+    }
+
+    /// Downloads a file from Zenoh-CDN using the given `options`.
+    ///
+    /// The file is written next to `destination` with a `.part` extension
+    /// and renamed once complete, it is removed if the download fails.
+    pub async fn download_with_options(
+        &self,
+        resource_name: &ResourceName,
+        destination: &Path,
+        options: &DownloadOptions,
+    ) -> CdnResult<PathBuf> {
+        let mut partial = destination.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = Path::new(&partial);
+
+        match self.download_to(resource_name, partial, options).await {
+            Ok(()) => {
+                rename_file(partial, destination).await?;
+                Ok(destination.into())
+            }
+            Err(e) => {
+                if let Err(e) = remove_file_if_exists(partial).await {
+                    log::warn!("Unable to clean up the download: {}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn download_to(
+        &self,
+        resource_name: &ResourceName,
+        destination: &Path,
+        options: &DownloadOptions,
+    ) -> CdnResult<()> {
+        let ws = self.z.workspace(None).await?;
+        let deadline = self.deadline();
+        let servers = self.servers().await.unwrap_or_else(|e| {
+            log::warn!("Unable to discover the servers: {:?}", e);
+            Vec::new()
+        });
+
+        let mut error = None;
+        for attempt in 1..=DEFAULT_DOWNLOAD_ATTEMPTS {
+            let metadata = self
+                .get_metadata(&ws, &servers, resource_name, deadline)
+                .await?;
+            let key = match &metadata.encryption {
+                Some(encryption) => Some(
+                    options
+                        .keys
+                        .iter()
+                        .find(|key| key.id == encryption.key_id)
+                        .ok_or_else(|| {
+                            CdnError::Other(format!(
+                                "File {:?} is encrypted with the unknown key {:?}",
+                                resource_name, encryption.key_id
+                            ))
+                        })?,
+                ),
+                None => None,
+            };
+            let tracker = Tracker::new(
+                options.progress.as_ref(),
+                options.cancel.as_ref(),
+                options.rate_limit,
+                self.limiter.as_deref(),
+                metadata.size,
+            );
+            if attempt > 1 {
+                tracker.restarted(attempt);
+            }
+
+            if !self
+                .get_chunks(
+                    &ws,
+                    &servers,
+                    resource_name,
+                    &metadata,
+                    destination,
+                    deadline,
+                    &tracker,
+                    key,
+                )
+                .await?
+            {
+                log::warn!(
+                    "File {:?} changed during download (attempt {}), restarting",
+                    resource_name,
+                    attempt
+                );
+                continue;
+            }
+
+            // The chunks of an encrypted file are authenticated along with
+            // its generation, size and layout when decrypted, its checksum
+            // only covers their digests.
+            let checksum = match metadata.encryption {
+                Some(_) => metadata.digests_checksum(),
+                None => hash(destination),
+            };
+            if checksum == metadata.checksum {
+                return Ok(());
+            }
+            log::warn!(
+                "Checksum mismatch for {:?} (attempt {}): expected {:?} got {:?}, restarting",
+                resource_name,
+                attempt,
+                metadata.checksum,
+                checksum
+            );
+            error = Some(CdnError::Integrity(format!(
+                "File {:?} expected checksum {:?} got {:?}",
+                resource_name, metadata.checksum, checksum
+            )));
+        }
+
+        Err(error
+            .unwrap_or_else(|| {
+                CdnError::Other(format!(
+                    "File {:?} kept changing during download",
+                    resource_name
+                ))
+            })
+            .context(&format!(
+                "Giving up after {} attempts",
+                DEFAULT_DOWNLOAD_ATTEMPTS
+            )))
+    }
+
+    /// Orders the servers by their measured latency, the ones never
+    /// measured come last.
+    fn rank_servers(&self, servers: &[ServerInfo]) -> Vec<String> {
+        let stats = self.stats.lock().unwrap();
+        let mut ranked = servers
+            .iter()
+            .map(|s| s.id.clone())
+            .collect::<Vec<String>>();
+        ranked.sort_by_key(|id| {
+            stats
+                .get(id)
+                .and_then(|s| s.latency)
+                .unwrap_or(Duration::MAX)
+        });
+        ranked
+    }
+
+    /// Picks the server expected to deliver a chunk first given its
+    /// throughput and the chunks already requested to it, the servers never
+    /// measured are tried first.
+    fn pick_server(&self, servers: &[ServerInfo], tried: &[String]) -> Option<Inflight<'_>> {
+        let mut stats = self.stats.lock().unwrap();
+        let server = servers
+            .iter()
+            .filter(|s| !tried.contains(&s.id))
+            .map(|s| {
+                let stats = stats.get(&s.id).cloned().unwrap_or_default();
+                let throughput = stats.throughput.unwrap_or(f64::INFINITY);
+                let score = (stats.inflight + 1) as f64 / throughput;
+                (score, stats.inflight, &s.id)
+            })
+            .min_by(|a, b| {
+                a.0.partial_cmp(&b.0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a.1.cmp(&b.1))
+            })
+            .map(|(_, _, id)| id.clone())?;
+        stats.entry(server.clone()).or_default().inflight += 1;
+        Some(Inflight {
+            stats: &self.stats,
+            server,
+        })
+    }
+
+    fn record_transfer(&self, server: &str, bytes: usize, elapsed: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(server.to_string()).or_default();
+        stats.latency = Some(match stats.latency {
+            Some(previous) => (previous * 3 + elapsed) / 4,
+            None => elapsed,
+        });
+        if bytes > 0 {
+            let throughput = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+            stats.throughput = Some(match stats.throughput {
+                Some(previous) => (previous * 3.0 + throughput) / 4.0,
+                None => throughput,
+            });
+        }
+    }
+
+    /// Shifts the load away from a server that failed.
+    fn record_failure(&self, server: &str, elapsed: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(server.to_string()).or_default();
+        stats.latency = Some(stats.latency.unwrap_or(elapsed).max(elapsed) * 2);
+        stats.throughput = Some(stats.throughput.map_or(1.0, |t| t / 4.0));
+    }
+
+    /// Queries a single server for `path`, returning its reply if it has one.
+    async fn query_server(
+        &self,
+        ws: &Workspace<'_>,
+        server: &str,
+        path: &str,
+        properties: &[(&str, &str)],
+    ) -> CdnResult<Option<zenoh::Value>> {
+        let mut server_properties = properties.to_vec();
+        server_properties.push((SERVER_PROPERTY, server));
+        let selector = signed_selector(self.identity.as_ref(), path, &server_properties)?;
+        let ds = ws.get(&selector).await?;
+        Ok(ds
+            .collect::<Vec<zenoh::Data>>()
+            .await
+            .pop()
+            .map(|kv| kv.value))
+    }
+
+    /// Queries every server for `path`. Of the metadata replied, the
+    /// generation superseding the others is used, otherwise the first reply.
+    ///
+    /// Fails if the servers replied with metadata that cannot be ordered: the
+    /// same generation described differently, or different files uploaded
+    /// without a generation.
+    async fn query_any(
+        &self,
+        ws: &Workspace<'_>,
+        path: &str,
+        properties: &[(&str, &str)],
+    ) -> CdnResult<zenoh::Value> {
+        let selector = signed_selector(self.identity.as_ref(), path, properties)?;
+        let ds = ws.get(&selector).await?;
+        let data = ds.collect::<Vec<zenoh::Data>>().await;
+        if data.len() > 1 {
+            log::debug!("Got {} responses for {:?}", data.len(), path);
+        }
+
+        let mut first = None;
+        let mut latest: Option<(FileMetadata, zenoh::Value)> = None;
+        for kv in data {
+            let metadata = match &kv.value {
+                zenoh::Value::Json(value) => FileMetadata::deserialize(value).ok(),
+                _ => None,
+            };
+            match (metadata, &latest) {
+                (None, _) => {
+                    first.get_or_insert(kv.value);
+                }
+                (Some(metadata), None) => latest = Some((metadata, kv.value)),
+                (Some(metadata), Some((current, _))) => {
+                    let comparable = match metadata.checksum == current.checksum {
+                        true => metadata == *current,
+                        false => metadata.generation != 0 || current.generation != 0,
+                    };
+                    if !comparable {
+                        return Err(CdnError::AmbiguousReply(format!(
+                            "Servers replied with checksums {:?} and {:?} for {:?}",
+                            current.checksum, metadata.checksum, path
+                        )));
+                    }
+                    if metadata.supersedes(current) {
+                        latest = Some((metadata, kv.value));
                     }
                 }
-                _ => zerror!(ZErrorKind::Other {
-                    descr: format!(
-                        "Got more than one response with this filename {:?}",
-                        resource_name
-                    )
-                }),
             }
-        }?;
+        }
+        match latest.map(|(_, value)| value).or(first) {
+            Some(value) => Ok(value),
+            None => Err(CdnError::NotFound(format!("File {:?}", path))),
+        }
+    }
 
+    /// Gets the value at `path`, asking the known servers one at a time by
+    /// increasing latency, then any server when none of them replied.
+    async fn get_value(
+        &self,
+        ws: &Workspace<'_>,
+        servers: &[ServerInfo],
+        path: &str,
+        properties: &[(&str, &str)],
+    ) -> CdnResult<zenoh::Value> {
+        for server in self.rank_servers(servers) {
+            let start = Instant::now();
+            match self.query_server(ws, &server, path, properties).await {
+                Ok(Some(value)) => {
+                    self.record_transfer(&server, 0, start.elapsed());
+                    return Ok(value);
+                }
+                Ok(None) => log::debug!("Server {:?} has no {:?}", server, path),
+                Err(e) => {
+                    log::debug!("Server {:?} failed for {:?}: {:?}", server, path, e);
+                    self.record_failure(&server, start.elapsed());
+                }
+            }
+        }
+        self.query_any(ws, path, properties).await
+    }
+
+    /// Gets a chunk from the server picked by `pick_server`, moving to the
+    /// next one when it fails, does not hold the chunk or replies with data
+    /// that `decode` refuses.
+    async fn get_chunk_value<T, D>(
+        &self,
+        ws: &Workspace<'_>,
+        servers: &[ServerInfo],
+        path: &str,
+        properties: &[(&str, &str)],
+        decode: D,
+    ) -> CdnResult<T>
+    where
+        D: Fn(&zenoh::Value) -> CdnResult<Option<T>>,
+    {
+        let mut tried = Vec::new();
+        while let Some(inflight) = self.pick_server(servers, &tried) {
+            let server = inflight.server.clone();
+            let start = Instant::now();
+            let reply = self.query_server(ws, &server, path, properties).await;
+            drop(inflight);
+            // An invalid chunk is a failure of the server that sent it.
+            let reply = match reply {
+                Ok(Some(value)) => {
+                    decode(&value).map(|decoded| decoded.map(|decoded| (value, decoded)))
+                }
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(Some((value, decoded))) => {
+                    let bytes = match &value {
+                        zenoh::Value::Raw(_, buf) => buf.len(),
+                        _ => 0,
+                    };
+                    self.record_transfer(&server, bytes, start.elapsed());
+                    return Ok(decoded);
+                }
+                Ok(None) => log::debug!("Server {:?} has no {:?}", server, path),
+                Err(e) => {
+                    log::debug!("Server {:?} failed for {:?}: {:?}", server, path, e);
+                    self.record_failure(&server, start.elapsed());
+                }
+            }
+            tried.push(server);
+        }
+        decode(&self.query_any(ws, path, properties).await?)?
+            .ok_or_else(|| CdnError::NotFound(format!("No server replied with {:?}", path)))
+    }
+
+    async fn get_metadata(
+        &self,
+        ws: &Workspace<'_>,
+        servers: &[ServerInfo],
+        resource_name: &ResourceName,
+        deadline: Option<Instant>,
+    ) -> CdnResult<FileMetadata> {
+        let path = FILE_METADATA_PATH!(self.root, resource_name);
+        let what = format!("Download of metadata of {:?}", resource_name);
+        let value = self
+            .with_retries(&what, deadline, || self.get_value(ws, servers, &path, &[]))
+            .await?;
+        match value {
+            zenoh::Value::Json(value) => {
+                let metadata = FileMetadata::deserialize(&value)?;
+                verify_metadata(&metadata, &self.config.trusted_publishers)?;
+                Ok(metadata)
+            }
+            value => Err(CdnError::MalformedMetadata(format!(
+                "Metadata is not correctly formatted {:?} - {:?}",
+                resource_name, value
+            ))),
+        }
+    }
+
+    /// Retrieves all the chunks of the generation described by `metadata`
+    /// into `destination`, returns `false` if a server reported that the
+    /// file has been overwritten in the meantime.
+    ///
+    /// Up to `DEFAULT_PARALLEL_CHUNKS` chunks are requested at the same time,
+    /// spread across the servers.
+    #[allow(clippy::too_many_arguments)]
+    async fn get_chunks(
+        &self,
+        ws: &Workspace<'_>,
+        servers: &[ServerInfo],
+        resource_name: &ResourceName,
+        metadata: &FileMetadata,
+        destination: &Path,
+        deadline: Option<Instant>,
+        tracker: &Tracker<'_>,
+        key: Option<&EncryptionKey>,
+    ) -> CdnResult<bool> {
         let destination_file = create_destination_file(destination, metadata.size).await?;
 
-        for i in 0..metadata.chunks {
-            let selector = Selector::try_from(FILE_CHUNK_PATH!(self.root, resource_name, i))?;
-            let data = {
-                let ds = ws.get(&selector).await?;
-
-                // Not sure this is needed...
-                let data = ds.collect::<Vec<zenoh::Data>>().await;
-
-                match data.len() {
-                    0 => zerror!(ZErrorKind::Other {
-                        descr: format!("File not found {:?}", resource_name)
-                    }),
-                    1 => {
-                        let kv = &data[0];
-                        match &kv.value {
-                            zenoh::Value::Raw(_, buf) => Ok(buf.to_vec()),
-                            _ => zerror!(ZErrorKind::Other {
-                                descr: format!(
-                                    "File data format is not correctly formatted {:?} - {:?}",
-                                    resource_name, kv
-                                )
-                            }),
-                        }
-                    }
-                    _ => zerror!(ZErrorKind::Other {
-                        descr: format!(
-                            "Got more than one response with this filename {:?}",
-                            resource_name
-                        )
-                    }),
+        let requests = (0..metadata.chunks).map(|i| async move {
+            if tracker.cancelled() {
+                return Err(CdnError::Cancelled(format!(
+                    "Download of {:?} cancelled",
+                    resource_name
+                )));
+            }
+            let offset = (i * metadata.chunk_size) as u64;
+            let len = metadata.size.saturating_sub(offset) as usize;
+            tracker.throttle(len.min(metadata.chunk_size)).await;
+            tracker.started(i);
+            let chunk = self
+                .get_chunk(ws, servers, resource_name, metadata, key, i, deadline)
+                .await
+                .map(|data| data.map(|data| (i, data)));
+            if let Err(e) = &chunk {
+                tracker.failed(i, e);
+            }
+            chunk
+        });
+        let mut chunks = futures::StreamExt::buffer_unordered(
+            futures::stream::iter(requests),
+            DEFAULT_PARALLEL_CHUNKS,
+        );
+        let mut cancelled = None;
+        while let Some(chunk) = chunks.next().await {
+            // Once cancelled, the requests not yet issued fail immediately
+            // and the ones in flight are awaited.
+            let chunk = match chunk {
+                Err(e @ CdnError::Cancelled(_)) => {
+                    cancelled = Some(e);
+                    continue;
+                }
+                chunk => chunk?,
+            };
+            match chunk {
+                Some((i, data)) => {
+                    write_destination_file(&destination_file, &data, i, metadata.chunk_size)
+                        .await?;
+                    tracker.completed(i, data.len());
                 }
-            }?;
-            write_destination_file(&destination_file, &data, i, metadata.chunk_size).await?;
+                None if cancelled.is_none() => return Ok(false),
+                None => (),
+            }
         }
 
-        Ok(destination.into())
+        match cancelled {
+            Some(e) => Err(e),
+            None => Ok(true),
+        }
+    }
+
+    /// Retrieves and decodes a chunk, returns `None` if a server serves a
+    /// newer generation of the file. A server replying with an older
+    /// generation is skipped, and a chunk that does not match its digest or
+    /// cannot be decoded counts as a failure of the server that sent it.
+    #[allow(clippy::too_many_arguments)]
+    async fn get_chunk(
+        &self,
+        ws: &Workspace<'_>,
+        servers: &[ServerInfo],
+        resource_name: &ResourceName,
+        metadata: &FileMetadata,
+        key: Option<&EncryptionKey>,
+        i: usize,
+        deadline: Option<Instant>,
+    ) -> CdnResult<Option<Vec<u8>>> {
+        let path = FILE_CHUNK_PATH!(self.root, resource_name, i);
+        let properties = [(CHECKSUM_PROPERTY, metadata.checksum.as_str())];
+        let what = format!("Download of chunk {} of {:?}", i, resource_name);
+        let decode = |value: &zenoh::Value| match value {
+            zenoh::Value::Raw(_, buf) => {
+                decode_chunk(metadata, key, i, &buf.to_vec()).map(|data| Some(Some(data)))
+            }
+            zenoh::Value::Json(value) => {
+                let stale = StaleChunk::deserialize(value)?;
+                log::debug!("Chunk {} is stale {:?}", i, stale);
+                match (stale.generation, &stale.current) {
+                    (Some(generation), Some(current))
+                        if (generation, current) > (metadata.generation, &metadata.checksum) =>
+                    {
+                        Ok(Some(None))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            value => Err(CdnError::MalformedMetadata(format!(
+                "File data format is not correctly formatted {:?} - {:?}",
+                resource_name, value
+            ))),
+        };
+        self.with_retries(&what, deadline, || {
+            self.get_chunk_value(ws, servers, &path, &properties, decode)
+        })
+        .await
     }
 }
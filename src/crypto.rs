@@ -0,0 +1,778 @@
+//
+// Copyright (c) 2017, 2021 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+
+use crate::error::{CdnError, CdnResult};
+use crate::types::{
+    selector_with_properties, Encryption, FileMetadata, MetadataSignature, ServerInfo,
+    IDENTITY_PROPERTY, NONCE_PROPERTY, REQUEST_SIGNATURE_VALIDITY, SIGNATURE_PROPERTY,
+    TIMESTAMP_PROPERTY,
+};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::RngCore;
+use std::collections::{BTreeSet, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zenoh::{Properties, Selector};
+
+/// Length in bytes of the random prefix of the nonces of a file, the
+/// remaining 4 bytes are the chunk number.
+const NONCE_PREFIX_LEN: usize = 8;
+
+/// ChaCha20-Poly1305 key supplied by the caller, its `id` is recorded in the
+/// metadata so that the download can pick the right key.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    pub id: String,
+    key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({:?})", self.id)
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(id: &str, key: [u8; 32]) -> Self {
+        Self {
+            id: id.to_string(),
+            key,
+        }
+    }
+
+    /// Reads a key from its hex representation.
+    pub fn from_hex(id: &str, key: &str) -> CdnResult<Self> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(key.trim(), &mut bytes)
+            .map_err(|e| CdnError::Other(format!("Invalid key {:?}: {}", id, e)))?;
+        Ok(Self::new(id, bytes))
+    }
+
+    /// Describes the encryption of a new file with this key.
+    pub fn encryption(&self) -> Encryption {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        Encryption {
+            key_id: self.id.clone(),
+            nonce: hex::encode(prefix),
+        }
+    }
+
+    /// Encrypts a chunk of the file described by `metadata`. The resource
+    /// name, generation, size and layout of the file and the chunk number are
+    /// authenticated so that chunks cannot be swapped between positions, files
+    /// or generations, nor accepted under truncated metadata.
+    pub fn encrypt(
+        &self,
+        metadata: &FileMetadata,
+        chunk: usize,
+        data: &[u8],
+    ) -> CdnResult<Vec<u8>> {
+        let (nonce, aad) = chunk_context(metadata, chunk)?;
+        self.cipher()
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: data,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|e| CdnError::Other(format!("Unable to encrypt chunk {}: {:?}", chunk, e)))
+    }
+
+    pub fn decrypt(
+        &self,
+        metadata: &FileMetadata,
+        chunk: usize,
+        data: &[u8],
+    ) -> CdnResult<Vec<u8>> {
+        let (nonce, aad) = chunk_context(metadata, chunk)?;
+        self.cipher()
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: data,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                CdnError::Integrity(format!(
+                    "Chunk {} of {:?} cannot be authenticated with key {:?}",
+                    chunk, metadata.resource_name, self.id
+                ))
+            })
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.key))
+    }
+}
+
+/// Nonce and associated data of a chunk of an encrypted file.
+fn chunk_context(metadata: &FileMetadata, chunk: usize) -> CdnResult<([u8; 12], String)> {
+    let encryption = metadata.encryption.as_ref().ok_or_else(|| {
+        CdnError::MalformedMetadata(format!("{:?} is not encrypted", metadata.resource_name))
+    })?;
+    let aad = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        metadata.resource_name,
+        metadata.generation,
+        metadata.size,
+        metadata.chunk_size,
+        metadata.chunks,
+        chunk
+    );
+    Ok((chunk_nonce(encryption, chunk)?, aad))
+}
+
+fn chunk_nonce(encryption: &Encryption, chunk: usize) -> CdnResult<[u8; 12]> {
+    let mut nonce = [0u8; 12];
+    hex::decode_to_slice(&encryption.nonce, &mut nonce[..NONCE_PREFIX_LEN]).map_err(|e| {
+        CdnError::MalformedMetadata(format!("Invalid nonce {:?}: {}", encryption.nonce, e))
+    })?;
+    let chunk = u32::try_from(chunk)
+        .map_err(|_| CdnError::Other(format!("Chunk number {} is too large", chunk)))?;
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&chunk.to_be_bytes());
+    Ok(nonce)
+}
+
+/// Ed25519 key of a publisher, signing the metadata of the files it uploads.
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.public_key())
+    }
+}
+
+impl Clone for SigningKey {
+    fn clone(&self) -> Self {
+        Self {
+            keypair: Keypair::from_bytes(&self.keypair.to_bytes()).unwrap(),
+        }
+    }
+}
+
+impl SigningKey {
+    /// Reads a key from the hex representation of its 32 bytes secret.
+    pub fn from_hex(secret: &str) -> CdnResult<Self> {
+        let bytes = hex::decode(secret.trim())
+            .map_err(|e| CdnError::Other(format!("Invalid signing key: {}", e)))?;
+        let secret = SecretKey::from_bytes(&bytes)
+            .map_err(|e| CdnError::Other(format!("Invalid signing key: {}", e)))?;
+        let public = PublicKey::from(&secret);
+        Ok(Self {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    /// Hex representation of the public key, as listed in the trusted
+    /// publishers.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.keypair.public.as_bytes())
+    }
+
+    /// Signs `metadata`, replacing any previous signature.
+    pub fn sign(&self, metadata: &mut FileMetadata) -> CdnResult<()> {
+        metadata.signature = None;
+        let signature = self.keypair.sign(metadata.serialize()?.as_bytes());
+        metadata.signature = Some(MetadataSignature {
+            public_key: self.public_key(),
+            signature: hex::encode(signature.to_bytes()),
+        });
+        Ok(())
+    }
+
+    /// Signs the heartbeat `info` of a server at the current time.
+    pub fn sign_server_info(&self, info: &mut ServerInfo) -> CdnResult<()> {
+        info.signature = None;
+        info.timestamp = now();
+        let signature = self.keypair.sign(info.serialize()?.as_bytes());
+        info.signature = Some(MetadataSignature {
+            public_key: self.public_key(),
+            signature: hex::encode(signature.to_bytes()),
+        });
+        Ok(())
+    }
+}
+
+/// Returns the public key of the publisher of `metadata` after checking its
+/// signature, `None` when it is not signed.
+pub fn metadata_publisher(metadata: &FileMetadata) -> CdnResult<Option<String>> {
+    let signature = match &metadata.signature {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let mut unsigned = metadata.clone();
+    unsigned.signature = None;
+    verify(
+        &signature.public_key,
+        &signature.signature,
+        unsigned.serialize()?.as_bytes(),
+    )
+    .map_err(|e| {
+        CdnError::Integrity(format!(
+            "Invalid signature of the metadata of {:?}: {}",
+            metadata.resource_name, e
+        ))
+    })?;
+    Ok(Some(signature.public_key.to_lowercase()))
+}
+
+/// Returns the public key of the server that signed the heartbeat `info`
+/// after checking its signature and that it is recent, `None` when it is not
+/// signed.
+pub fn server_info_signer(info: &ServerInfo) -> CdnResult<Option<String>> {
+    let signature = match &info.signature {
+        Some(signature) => signature,
+        None => return Ok(None),
+    };
+    let mut unsigned = info.clone();
+    unsigned.signature = None;
+    verify(
+        &signature.public_key,
+        &signature.signature,
+        unsigned.serialize()?.as_bytes(),
+    )
+    .map_err(|e| {
+        CdnError::Integrity(format!(
+            "Invalid signature of the heartbeat of {:?}: {}",
+            info.id, e
+        ))
+    })?;
+    let now = now();
+    if now.max(info.timestamp) - now.min(info.timestamp) > REQUEST_SIGNATURE_VALIDITY {
+        return Err(CdnError::Denied(format!(
+            "Expired signature of the heartbeat of {:?}",
+            info.id
+        )));
+    }
+    Ok(Some(signature.public_key.to_lowercase()))
+}
+
+/// Checks that `metadata` is signed by one of the `trusted` publishers, any
+/// metadata is accepted when there are none.
+pub fn verify_metadata(metadata: &FileMetadata, trusted: &[String]) -> CdnResult<()> {
+    if trusted.is_empty() {
+        return Ok(());
+    }
+    match metadata_publisher(metadata)? {
+        Some(publisher)
+            if trusted
+                .iter()
+                .any(|key| key.eq_ignore_ascii_case(&publisher)) =>
+        {
+            Ok(())
+        }
+        Some(publisher) => Err(CdnError::Integrity(format!(
+            "Metadata of {:?} is signed by the untrusted publisher {:?}",
+            metadata.resource_name, publisher
+        ))),
+        None => Err(CdnError::Integrity(format!(
+            "Metadata of {:?} is not signed",
+            metadata.resource_name
+        ))),
+    }
+}
+
+/// Builds the selector of a query on `path`, signed when there is an
+/// `identity`.
+pub fn signed_selector(
+    identity: Option<&SigningKey>,
+    path: &str,
+    properties: &[(&str, &str)],
+) -> CdnResult<Selector> {
+    let signed = match identity {
+        Some(key) => sign_request(key, path, properties),
+        None => Vec::new(),
+    };
+    let mut properties = properties.to_vec();
+    properties.extend(signed.iter().map(|(k, v)| (*k, v.as_str())));
+    Ok(Selector::try_from(selector_with_properties(
+        path,
+        &properties,
+    ))?)
+}
+
+/// Properties authenticating a query on `path` with `properties` as sent by
+/// the owner of `key`. A random nonce makes every signature unique.
+pub fn sign_request(
+    key: &SigningKey,
+    path: &str,
+    properties: &[(&str, &str)],
+) -> Vec<(&'static str, String)> {
+    let timestamp = now().to_string();
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let nonce = hex::encode(nonce);
+
+    let mut signed = properties.to_vec();
+    signed.push((TIMESTAMP_PROPERTY, &timestamp));
+    signed.push((NONCE_PROPERTY, &nonce));
+    let signature = key
+        .keypair
+        .sign(request_message(path, signed.into_iter()).as_bytes());
+    vec![
+        (TIMESTAMP_PROPERTY, timestamp),
+        (NONCE_PROPERTY, nonce),
+        (IDENTITY_PROPERTY, key.public_key()),
+        (SIGNATURE_PROPERTY, hex::encode(signature.to_bytes())),
+    ]
+}
+
+/// Returns the identity that signed a query on `path`, `None` when it is not
+/// signed. The signature covers all the other properties, signatures older
+/// than `REQUEST_SIGNATURE_VALIDITY` are refused.
+pub fn verify_request(path: &str, properties: &Properties) -> CdnResult<Option<String>> {
+    let (identity, signature, timestamp) = match (
+        properties.get(IDENTITY_PROPERTY),
+        properties.get(SIGNATURE_PROPERTY),
+        properties.get(TIMESTAMP_PROPERTY),
+    ) {
+        (Some(identity), Some(signature), Some(timestamp)) => (identity, signature, timestamp),
+        (None, None, _) => return Ok(None),
+        _ => {
+            return Err(CdnError::Denied(format!(
+                "Incomplete signature of the query on {:?}",
+                path
+            )))
+        }
+    };
+
+    let now = now();
+    match timestamp.parse::<u64>() {
+        Ok(timestamp) if now.max(timestamp) - now.min(timestamp) <= REQUEST_SIGNATURE_VALIDITY => {}
+        _ => {
+            return Err(CdnError::Denied(format!(
+                "Expired signature of the query on {:?}",
+                path
+            )))
+        }
+    }
+
+    let signed = properties
+        .iter()
+        .filter(|(k, _)| k.as_str() != IDENTITY_PROPERTY && k.as_str() != SIGNATURE_PROPERTY)
+        .map(|(k, v)| (k.as_str(), v.as_str()));
+    verify(
+        identity,
+        signature,
+        request_message(path, signed).as_bytes(),
+    )
+    .map_err(|e| {
+        CdnError::Denied(format!(
+            "Invalid signature of the query on {:?}: {}",
+            path, e
+        ))
+    })?;
+    Ok(Some(identity.to_lowercase()))
+}
+
+/// Signed message of a query: its path then its properties sorted by key.
+fn request_message<'a>(path: &str, properties: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let mut properties = properties
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>();
+    properties.sort();
+    // Zenoh collapses repeated slashes when parsing the selector.
+    let mut canonical = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !canonical.ends_with('/') {
+            canonical.push(c);
+        }
+    }
+    format!("{}\n{}", canonical, properties.join("\n"))
+}
+
+/// Signatures of the queries verified recently, refusing those replayed while
+/// their timestamp is still valid.
+#[derive(Default)]
+pub struct ReplayGuard {
+    seen: Mutex<SeenSignatures>,
+}
+
+#[derive(Default)]
+struct SeenSignatures {
+    signatures: HashSet<String>,
+    /// Signatures by the timestamp of their query, oldest first.
+    by_timestamp: BTreeSet<(u64, String)>,
+}
+
+impl SeenSignatures {
+    /// Records `signature`, returning whether it was not seen yet. The
+    /// signatures whose timestamp left the validity window are forgotten on
+    /// every insert: `verify_request` refuses them anyway, and the memory
+    /// stays bounded by the queries of the window.
+    fn insert(&mut self, signature: String, timestamp: u64, now: u64) -> bool {
+        while let Some(oldest) = self.by_timestamp.iter().next().cloned() {
+            if oldest.0.saturating_add(REQUEST_SIGNATURE_VALIDITY) >= now {
+                break;
+            }
+            self.signatures.remove(&oldest.1);
+            self.by_timestamp.remove(&oldest);
+        }
+        if !self.signatures.insert(signature.clone()) {
+            return false;
+        }
+        self.by_timestamp.insert((timestamp, signature));
+        true
+    }
+}
+
+impl ReplayGuard {
+    /// Verifies a query as `verify_request` does, then refuses it if its
+    /// signature was already seen.
+    pub fn verify(&self, path: &str, properties: &Properties) -> CdnResult<Option<String>> {
+        let identity = verify_request(path, properties)?;
+        let (signature, timestamp) = match (
+            &identity,
+            properties.get(SIGNATURE_PROPERTY),
+            properties
+                .get(TIMESTAMP_PROPERTY)
+                .and_then(|timestamp| timestamp.parse().ok()),
+        ) {
+            (Some(_), Some(signature), Some(timestamp)) => (signature.to_lowercase(), timestamp),
+            _ => return Ok(identity),
+        };
+
+        match self
+            .seen
+            .lock()
+            .unwrap()
+            .insert(signature, timestamp, now())
+        {
+            true => Ok(identity),
+            false => Err(CdnError::Denied(format!(
+                "Replayed signature of the query on {:?}",
+                path
+            ))),
+        }
+    }
+}
+
+/// Seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Checks the hex encoded `signature` of `message` by `public_key`.
+fn verify(public_key: &str, signature: &str, message: &[u8]) -> Result<(), String> {
+    let public_key = hex::decode(public_key)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).map_err(|e| e.to_string()))?;
+    let signature = hex::decode(signature)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Signature::try_from(&bytes[..]).map_err(|e| e.to_string()))?;
+    public_key
+        .verify(message, &signature)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> FileMetadata {
+        FileMetadata::deserialize(
+            r#"{"filename":"file.bin","checksum":"0123456789ABCDEF0123456789ABCDEF",
+                "chunk_size":1024,"chunks":2,"resource_name":"/a/file.bin","size":1500}"#,
+        )
+        .unwrap()
+    }
+
+    fn signing_key(byte: u8) -> SigningKey {
+        SigningKey::from_hex(&hex::encode([byte; 32])).unwrap()
+    }
+
+    fn properties(pairs: &[(&str, String)]) -> Properties {
+        let mut properties = Properties::default();
+        for (k, v) in pairs {
+            properties.insert(k.to_string(), v.clone());
+        }
+        properties
+    }
+
+    fn encrypted_metadata(key: &EncryptionKey) -> FileMetadata {
+        let mut metadata = metadata();
+        metadata.encryption = Some(key.encryption());
+        metadata
+    }
+
+    #[test]
+    fn encryption_round_trip() {
+        let key = EncryptionKey::new("key", [7; 32]);
+        let metadata = encrypted_metadata(&key);
+        let data = b"some chunk data".to_vec();
+        let encrypted = key.encrypt(&metadata, 1, &data).unwrap();
+        assert_ne!(encrypted, data);
+        assert_eq!(key.decrypt(&metadata, 1, &encrypted).unwrap(), data);
+
+        let from_hex = EncryptionKey::from_hex("key", &hex::encode([7; 32])).unwrap();
+        assert_eq!(from_hex.decrypt(&metadata, 1, &encrypted).unwrap(), data);
+        assert!(key.encrypt(&self::metadata(), 1, &data).is_err());
+    }
+
+    #[test]
+    fn encryption_uses_a_new_nonce_per_file() {
+        let key = EncryptionKey::new("key", [7; 32]);
+        let (first, second) = (encrypted_metadata(&key), encrypted_metadata(&key));
+        assert_ne!(
+            first.encryption.as_ref().unwrap().nonce,
+            second.encryption.as_ref().unwrap().nonce
+        );
+        assert_ne!(
+            key.encrypt(&first, 0, b"data").unwrap(),
+            key.encrypt(&second, 0, b"data").unwrap()
+        );
+    }
+
+    #[test]
+    fn decryption_detects_tampering() {
+        let key = EncryptionKey::new("key", [7; 32]);
+        let metadata = encrypted_metadata(&key);
+        let encrypted = key.encrypt(&metadata, 1, b"data").unwrap();
+
+        for i in 0..encrypted.len() {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 1;
+            assert!(key.decrypt(&metadata, 1, &tampered).is_err());
+        }
+        let truncated = &encrypted[..encrypted.len() - 1];
+        assert!(key.decrypt(&metadata, 1, truncated).is_err());
+        // Chunks cannot be moved to another position or file.
+        assert!(key.decrypt(&metadata, 0, &encrypted).is_err());
+        let tampers: Vec<fn(&mut FileMetadata)> = vec![
+            |m| m.resource_name = "/b/file.bin".to_string(),
+            |m| m.generation += 1,
+            |m| m.size -= 1,
+            |m| m.chunks -= 1,
+            |m| m.chunk_size *= 2,
+        ];
+        for tamper in tampers {
+            let mut other = metadata.clone();
+            tamper(&mut other);
+            assert!(key.decrypt(&other, 1, &encrypted).is_err());
+        }
+
+        let other = EncryptionKey::new("key", [8; 32]);
+        assert!(other.decrypt(&metadata, 1, &encrypted).is_err());
+        let other_file = encrypted_metadata(&key);
+        assert!(key.decrypt(&other_file, 1, &encrypted).is_err());
+    }
+
+    #[test]
+    fn invalid_encryption_keys_are_refused() {
+        assert!(EncryptionKey::from_hex("key", "00").is_err());
+        assert!(EncryptionKey::from_hex("key", &"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn signed_metadata_round_trip() {
+        let key = signing_key(1);
+        let mut metadata = metadata();
+        key.sign(&mut metadata).unwrap();
+
+        let metadata = FileMetadata::deserialize(&metadata.serialize().unwrap()).unwrap();
+        assert!(verify_metadata(&metadata, &[]).is_ok());
+        assert!(verify_metadata(&metadata, &[key.public_key()]).is_ok());
+        assert!(verify_metadata(&metadata, &[key.public_key().to_uppercase()]).is_ok());
+        assert!(verify_metadata(&metadata, &[signing_key(2).public_key()]).is_err());
+    }
+
+    #[test]
+    fn tampered_metadata_is_refused() {
+        let key = signing_key(1);
+        let trusted = [key.public_key()];
+        let mut signed = metadata();
+        key.sign(&mut signed).unwrap();
+
+        let tampers: Vec<fn(&mut FileMetadata)> = vec![
+            |m| m.size += 1,
+            |m| m.chunks -= 1,
+            |m| m.checksum = "00".to_string(),
+            |m| m.resource_name = "/b/file.bin".to_string(),
+            |m| m.ttl = Some(1),
+        ];
+        for tamper in tampers {
+            let mut metadata = signed.clone();
+            tamper(&mut metadata);
+            assert!(verify_metadata(&metadata, &trusted).is_err());
+        }
+
+        // The signature of one publisher cannot be claimed by another.
+        let mut metadata = signed.clone();
+        if let Some(signature) = &mut metadata.signature {
+            signature.public_key = signing_key(2).public_key();
+        }
+        assert!(verify_metadata(&metadata, &[signing_key(2).public_key()]).is_err());
+
+        let mut unsigned = signed;
+        unsigned.signature = None;
+        assert!(verify_metadata(&unsigned, &trusted).is_err());
+    }
+
+    #[test]
+    fn metadata_publisher_is_the_verified_signer() {
+        let key = signing_key(1);
+        let mut metadata = metadata();
+        assert_eq!(metadata_publisher(&metadata).unwrap(), None);
+
+        key.sign(&mut metadata).unwrap();
+        assert_eq!(
+            metadata_publisher(&metadata).unwrap(),
+            Some(key.public_key())
+        );
+        metadata.size += 1;
+        assert!(metadata_publisher(&metadata).is_err());
+    }
+
+    #[test]
+    fn signed_requests_round_trip() {
+        let key = signing_key(1);
+        let signed = properties(&sign_request(&key, "/a/b", &[]));
+        assert_eq!(
+            verify_request("/a/b", &signed).unwrap(),
+            Some(key.public_key())
+        );
+        assert!(verify_request("/a/c", &signed).is_err());
+
+        let mut tampered = signed.clone();
+        let timestamp = tampered[TIMESTAMP_PROPERTY].parse::<u64>().unwrap() + 1;
+        tampered.insert(TIMESTAMP_PROPERTY.to_string(), timestamp.to_string());
+        assert!(verify_request("/a/b", &tampered).is_err());
+
+        let mut incomplete = signed;
+        incomplete.remove(SIGNATURE_PROPERTY);
+        assert!(verify_request("/a/b", &incomplete).is_err());
+
+        assert_eq!(
+            verify_request("/a/b", &Properties::default()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn signed_requests_cover_their_properties() {
+        let key = signing_key(1);
+        let mut pairs = vec![("checksum", "AB".to_string())];
+        pairs.extend(sign_request(&key, "/a/b", &[("checksum", "AB")]));
+        assert_eq!(
+            verify_request("/a/b", &properties(&pairs)).unwrap(),
+            Some(key.public_key())
+        );
+
+        let mut tampered = pairs.clone();
+        tampered[0].1 = "CD".to_string();
+        assert!(verify_request("/a/b", &properties(&tampered)).is_err());
+
+        let mut added = pairs.clone();
+        added.push(("server", "other".to_string()));
+        assert!(verify_request("/a/b", &properties(&added)).is_err());
+
+        let removed = &pairs[1..];
+        assert!(verify_request("/a/b", &properties(removed)).is_err());
+    }
+
+    #[test]
+    fn replayed_requests_are_refused() {
+        let key = signing_key(1);
+        let guard = ReplayGuard::default();
+        let first = properties(&sign_request(&key, "/a/b", &[]));
+        let second = properties(&sign_request(&key, "/a/b", &[]));
+
+        assert!(guard.verify("/a/b", &first).is_ok());
+        assert!(guard.verify("/a/b", &first).is_err());
+        assert!(guard.verify("/a/b", &second).is_ok());
+        let unsigned = Properties::default();
+        assert!(guard.verify("/a/b", &unsigned).is_ok());
+        assert!(guard.verify("/a/b", &unsigned).is_ok());
+    }
+
+    #[test]
+    fn expired_signatures_are_forgotten_on_insert() {
+        let mut seen = SeenSignatures::default();
+        assert!(seen.insert("a".to_string(), 1_000, 1_000));
+        assert!(seen.insert("b".to_string(), 1_200, 1_200));
+        assert!(!seen.insert("a".to_string(), 1_000, 1_000 + REQUEST_SIGNATURE_VALIDITY));
+
+        let now = 1_001 + REQUEST_SIGNATURE_VALIDITY;
+        assert!(seen.insert("c".to_string(), now, now));
+        assert!(!seen.signatures.contains("a"));
+        assert!(seen.signatures.contains("b"));
+        assert_eq!(seen.by_timestamp.len(), 2);
+    }
+
+    #[test]
+    fn signed_selectors_survive_parsing() {
+        let key = signing_key(1);
+        let path = crate::FILE_CHUNK_PATH!("/zenohcdn", "/a/b", 3);
+        assert_eq!(path, "/zenohcdn/files/a/b/3");
+        let selector = signed_selector(Some(&key), &path, &[("checksum", "AB")]).unwrap();
+        assert_eq!(selector.path_expr.as_str(), path);
+        assert_eq!(
+            verify_request(selector.path_expr.as_str(), &selector.properties).unwrap(),
+            Some(key.public_key())
+        );
+
+        let signed = properties(&sign_request(&key, "/zenohcdn/files//a/b/3", &[]));
+        assert!(verify_request("/zenohcdn/files/a/b/3", &signed).is_ok());
+    }
+
+    #[test]
+    fn signed_heartbeats_round_trip() {
+        let key = signing_key(1);
+        let mut info = ServerInfo {
+            id: "a".to_string(),
+            quota: None,
+            used_bytes: 10,
+            heartbeat_interval: 10,
+            timestamp: 0,
+            signature: None,
+        };
+        assert_eq!(server_info_signer(&info).unwrap(), None);
+
+        key.sign_server_info(&mut info).unwrap();
+        let info = ServerInfo::deserialize(&info.serialize().unwrap()).unwrap();
+        assert_eq!(server_info_signer(&info).unwrap(), Some(key.public_key()));
+
+        let mut impersonated = info.clone();
+        impersonated.id = "b".to_string();
+        assert!(server_info_signer(&impersonated).is_err());
+
+        let mut replayed = info;
+        replayed.signature = None;
+        replayed.timestamp -= REQUEST_SIGNATURE_VALIDITY + 1;
+        let signature = key.keypair.sign(replayed.serialize().unwrap().as_bytes());
+        replayed.signature = Some(MetadataSignature {
+            public_key: key.public_key(),
+            signature: hex::encode(signature.to_bytes()),
+        });
+        assert!(server_info_signer(&replayed).is_err());
+    }
+
+    #[test]
+    fn invalid_signing_keys_are_refused() {
+        assert!(SigningKey::from_hex("00").is_err());
+        assert!(SigningKey::from_hex(&"zz".repeat(32)).is_err());
+    }
+}
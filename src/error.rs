@@ -0,0 +1,119 @@
+//
+// Copyright (c) 2017, 2021 ADLINK Technology Inc.
+//
+// This program and the accompanying materials are made available under the
+// terms of the Eclipse Public License 2.0 which is available at
+// http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+// which is available at https://www.apache.org/licenses/LICENSE-2.0.
+//
+// SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+//
+// Contributors:
+//   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+//
+
+use std::fmt;
+use zenoh::{ZError, ZErrorKind};
+
+pub type CdnResult<T> = Result<T, CdnError>;
+
+#[derive(Debug)]
+pub enum CdnError {
+    /// The file, its metadata or one of its chunks is not stored anywhere.
+    NotFound(String),
+    /// The content does not match its checksum.
+    Integrity(String),
+    /// Metadata or a reply that cannot be parsed.
+    MalformedMetadata(String),
+    /// Error when accessing the local file system.
+    Io(String),
+    /// Servers replied with conflicting values.
+    AmbiguousReply(String),
+    /// A request did not complete in time.
+    Timeout(String),
+    /// A resource name or filename that is malformed or unsafe.
+    InvalidName(String),
+    /// The request is not allowed by the access control rules.
+    Denied(String),
+    /// The transfer was cancelled by the caller.
+    Cancelled(String),
+    /// The server has no room for the file within its quota.
+    QuotaExceeded(String),
+    /// Error reported by zenoh.
+    Zenoh(ZError),
+    Other(String),
+}
+
+impl CdnError {
+    /// Whether retrying the request may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CdnError::Timeout(_) | CdnError::Io(_) | CdnError::Zenoh(_)
+        )
+    }
+
+    /// Prefixes the description of the error with `context`.
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            CdnError::NotFound(descr) => CdnError::NotFound(format!("{}: {}", context, descr)),
+            CdnError::Integrity(descr) => CdnError::Integrity(format!("{}: {}", context, descr)),
+            CdnError::MalformedMetadata(descr) => {
+                CdnError::MalformedMetadata(format!("{}: {}", context, descr))
+            }
+            CdnError::Io(descr) => CdnError::Io(format!("{}: {}", context, descr)),
+            CdnError::AmbiguousReply(descr) => {
+                CdnError::AmbiguousReply(format!("{}: {}", context, descr))
+            }
+            CdnError::Timeout(descr) => CdnError::Timeout(format!("{}: {}", context, descr)),
+            CdnError::InvalidName(descr) => {
+                CdnError::InvalidName(format!("{}: {}", context, descr))
+            }
+            CdnError::Denied(descr) => CdnError::Denied(format!("{}: {}", context, descr)),
+            CdnError::Cancelled(descr) => CdnError::Cancelled(format!("{}: {}", context, descr)),
+            CdnError::QuotaExceeded(descr) => {
+                CdnError::QuotaExceeded(format!("{}: {}", context, descr))
+            }
+            CdnError::Zenoh(e) => CdnError::Other(format!("{}: {}", context, e)),
+            CdnError::Other(descr) => CdnError::Other(format!("{}: {}", context, descr)),
+        }
+    }
+}
+
+impl fmt::Display for CdnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdnError::NotFound(descr) => write!(f, "Not found: {}", descr),
+            CdnError::Integrity(descr) => write!(f, "Integrity check failed: {}", descr),
+            CdnError::MalformedMetadata(descr) => write!(f, "Malformed metadata: {}", descr),
+            CdnError::Io(descr) => write!(f, "I/O error: {}", descr),
+            CdnError::AmbiguousReply(descr) => write!(f, "Ambiguous reply: {}", descr),
+            CdnError::Timeout(descr) => write!(f, "Timeout: {}", descr),
+            CdnError::InvalidName(descr) => write!(f, "Invalid name: {}", descr),
+            CdnError::Denied(descr) => write!(f, "Access denied: {}", descr),
+            CdnError::Cancelled(descr) => write!(f, "Cancelled: {}", descr),
+            CdnError::QuotaExceeded(descr) => write!(f, "Quota exceeded: {}", descr),
+            CdnError::Zenoh(e) => write!(f, "Zenoh error: {}", e),
+            CdnError::Other(descr) => write!(f, "{}", descr),
+        }
+    }
+}
+
+impl std::error::Error for CdnError {}
+
+impl From<ZError> for CdnError {
+    fn from(e: ZError) -> Self {
+        CdnError::Zenoh(e)
+    }
+}
+
+impl From<CdnError> for ZError {
+    fn from(e: CdnError) -> Self {
+        match e {
+            CdnError::Zenoh(e) => e,
+            e => zenoh_util::zerror2!(ZErrorKind::Other {
+                descr: e.to_string()
+            }),
+        }
+    }
+}
@@ -13,6 +13,8 @@
 //
 
 pub mod client;
+pub mod crypto;
+pub mod error;
 pub mod server;
 pub mod types;
 pub mod utils;
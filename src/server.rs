@@ -12,51 +12,437 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 
-use crate::types::FILES_KEY;
+use crate::crypto::{
+    metadata_publisher, server_info_signer, signed_selector, verify_metadata, ReplayGuard,
+    SigningKey,
+};
+use crate::error::{CdnError, CdnResult};
+use crate::types::{
+    acl_rule, extract_chunk_number, extract_complete_file_path, extract_file_path, hash_path,
+    is_hashed_path, rendezvous_owners, Access, AclRule, AdminStatus, FileMetadata, FileStatus,
+    GcReport, PartialUpload, RecentError, ScrubConfig, ServerConfig, ServerInfo, StaleChunk,
+    UploadCommit,
+};
+use crate::{
+    ADMIN_PATH, FILE_CHUNK_PATH, FILE_COMMIT_PATH, FILE_METADATA_PATH, LIST_FILE_PATH,
+    LIST_SERVERS_PATH, SERVER_INFO_PATH,
+};
+
 use crate::types::{
-    extract_chunk_number, extract_complete_file_path, extract_file_path, hash_path, FileMetadata,
-    ServerConfig,
+    ADMIN_KEY, CACHED_FILE, CHECKSUM_PROPERTY, COMMITTED_FILE, FETCH_PROPERTY, FILES_KEY,
+    MAX_HEARTBEAT_INTERVAL, MAX_RECENT_ERRORS, PENDING_FILE, QUARANTINE_DIR, SEPARATOR,
+    SERVER_ID_FILE, SERVER_PROPERTY, STAGING_DIR,
 };
 
 use crate::utils::{
-    create_dir_if_not_exists, read_file_to_string, read_file_to_vec, write_chunk_file,
-    write_metadata_file,
+    create_dir_if_not_exists, list_dirs, list_files, modified_time, path_exists,
+    read_file_to_string, read_file_to_vec, remove_dir_if_exists, remove_file_if_exists,
+    rename_file, write_chunk_file, write_metadata_file, RateLimiter,
 };
 
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Mutex, RwLock};
 use async_std::task::JoinHandle;
 use futures::prelude::*;
 use futures::select;
 use futures::StreamExt;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::TryFrom;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use zenoh::{Change, ChangeKind, GetRequest, Value, ZError, ZErrorKind, ZResult, Zenoh};
+use zenoh::{Change, ChangeKind, GetRequest, Properties, Value, Zenoh};
 use zenoh::{Path as ZPath, PathExpr, Selector};
-use zenoh_util::{zerror, zerror2};
 
 pub fn hash(filename: &Path) -> String {
     checksums::hash_file(filename, checksums::Algorithm::MD5)
 }
 
+/// Index entry of a file stored in `chunks_dir`.
+#[derive(Debug, Clone)]
+struct StoredFile {
+    /// Generation served, `None` until an upload of the file completes.
+    metadata: Option<FileMetadata>,
+    metadata_len: u64,
+    /// Size of every stored chunk of the generation served.
+    chunks: BTreeMap<usize, u64>,
+    /// Bytes stored for the file, uploads in progress included.
+    size: u64,
+    last_access: SystemTime,
+    /// When the metadata served was stored, the age of the files uploaded
+    /// without a generation.
+    stored: SystemTime,
+    /// Uploads in progress, by checksum.
+    staged: HashMap<String, StagedUpload>,
+    /// Chunks of the generation served found corrupted since the server
+    /// started and not stored again.
+    quarantined: BTreeSet<usize>,
+    /// The generation served was retrieved by the pull-through cache, its
+    /// chunks are only fetched on demand.
+    cached: bool,
+}
+
+/// Generation of a file being uploaded, stored in `STAGING_DIR` until it is
+/// committed and all the chunks this server owns are verified.
+#[derive(Debug, Clone)]
+struct StagedUpload {
+    metadata: FileMetadata,
+    metadata_len: u64,
+    /// Size of every stored chunk, all matching their digest.
+    chunks: BTreeMap<usize, u64>,
+    size: u64,
+    committed: bool,
+    started: SystemTime,
+    /// Last time a chunk was staged or the upload committed.
+    touched: SystemTime,
+}
+
+impl StoredFile {
+    fn new() -> Self {
+        Self {
+            metadata: None,
+            metadata_len: 0,
+            chunks: BTreeMap::new(),
+            size: 0,
+            last_access: SystemTime::now(),
+            stored: SystemTime::now(),
+            staged: HashMap::new(),
+            quarantined: BTreeSet::new(),
+            cached: false,
+        }
+    }
+
+    fn complete(&self) -> bool {
+        self.metadata.is_some() && !self.cached
+    }
+
+    fn is_empty(&self) -> bool {
+        self.metadata.is_none() && self.chunks.is_empty() && self.staged.is_empty()
+    }
+
+    fn resource_name(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .or_else(|| self.staged.values().next().map(|upload| &upload.metadata))
+            .map(|metadata| metadata.resource_name.as_str())
+    }
+
+    fn set_metadata(&mut self, metadata: FileMetadata, len: u64) {
+        self.size = self.size - self.metadata_len + len;
+        self.metadata = Some(metadata);
+        self.metadata_len = len;
+        self.stored = SystemTime::now();
+    }
+
+    fn set_chunk(&mut self, chunk_number: usize, len: u64) {
+        self.quarantined.remove(&chunk_number);
+        let previous = self.chunks.insert(chunk_number, len).unwrap_or(0);
+        self.size = self.size - previous + len;
+    }
+
+    fn remove_chunk(&mut self, chunk_number: usize) {
+        self.size -= self.chunks.remove(&chunk_number).unwrap_or(0);
+    }
+
+    /// Starts an upload, kept with the chunks already staged when the same
+    /// generation is announced again. Returns whether an upload with the same
+    /// checksum but other metadata was replaced, its chunks dropped.
+    fn stage(&mut self, metadata: FileMetadata, len: u64) -> bool {
+        let replaced = match self.staged.get(&metadata.checksum) {
+            Some(upload) if upload.metadata == metadata => return false,
+            Some(_) => {
+                self.remove_staged(&metadata.checksum);
+                true
+            }
+            None => false,
+        };
+        self.size += len;
+        self.staged.insert(
+            metadata.checksum.clone(),
+            StagedUpload {
+                metadata,
+                metadata_len: len,
+                chunks: BTreeMap::new(),
+                size: len,
+                committed: false,
+                started: SystemTime::now(),
+                touched: SystemTime::now(),
+            },
+        );
+        replaced
+    }
+
+    fn set_staged_chunk(&mut self, checksum: &str, chunk_number: usize, len: u64) {
+        if let Some(upload) = self.staged.get_mut(checksum) {
+            let previous = upload.chunks.insert(chunk_number, len).unwrap_or(0);
+            upload.size = upload.size - previous + len;
+            upload.touched = SystemTime::now();
+            self.size = self.size - previous + len;
+        }
+    }
+
+    fn remove_staged(&mut self, checksum: &str) {
+        if let Some(upload) = self.staged.remove(checksum) {
+            self.size -= upload.size;
+        }
+    }
+
+    /// Forgets the generation served, its metadata and chunks.
+    fn clear_served(&mut self) {
+        self.size -= self.metadata_len + self.chunks.values().sum::<u64>();
+        self.metadata = None;
+        self.metadata_len = 0;
+        self.chunks.clear();
+        self.quarantined.clear();
+        self.cached = false;
+    }
+
+    /// Bytes of the generation served that the upload `checksum` replaces
+    /// once complete, none when it does not supersede it.
+    fn superseded_size(&self, checksum: Option<&str>) -> u64 {
+        match (checksum.and_then(|c| self.staged.get(c)), &self.metadata) {
+            (Some(upload), Some(served)) if upload.metadata.supersedes(served) => {
+                self.metadata_len + self.chunks.values().sum::<u64>()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Serves the generation `checksum` in place of the current one.
+    fn promote(&mut self, checksum: &str) {
+        if let Some(upload) = self.staged.remove(checksum) {
+            self.clear_served();
+            self.metadata = Some(upload.metadata);
+            self.metadata_len = upload.metadata_len;
+            self.chunks = upload.chunks;
+            self.stored = SystemTime::now();
+        }
+    }
+}
+
+/// Where a chunk of a given generation of a file is stored.
+enum ChunkLocation {
+    Stored(PathBuf),
+    Missing,
+    /// The generation is neither served nor being uploaded, with the
+    /// checksum and generation of the one served.
+    Stale(Option<(String, u64)>),
+}
+
+/// Reads the directory of a file stored under `hashed_path`, also returns
+/// the inconsistencies found in it.
+async fn scan_file(dir: &Path, hashed_path: &str) -> CdnResult<(StoredFile, Vec<String>)> {
+    let mut file = StoredFile {
+        last_access: modified_time(dir).await?,
+        ..StoredFile::new()
+    };
+    let mut problems = Vec::new();
+    let mut interrupted = false;
+
+    let mut entries = async_std::fs::read_dir(dir)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to read folder {:?} {:?}", dir, e)))?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry.map_err(|e| {
+            CdnError::Io(format!("Unable to read folder entry in {:?} {:?}", dir, e))
+        })?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let (len, modified) = match entry.metadata().await {
+            Ok(metadata) if metadata.is_file() => (metadata.len(), metadata.modified().ok()),
+            _ => {
+                problems.push(format!("unexpected entry {:?}", name));
+                continue;
+            }
+        };
+        if let Some(modified) = modified {
+            file.last_access = file.last_access.max(modified);
+        }
+        file.size += len;
+        match name.as_str() {
+            "metadata" => match read_file_to_string(&dir.join(&name))
+                .await
+                .and_then(|metadata| FileMetadata::deserialize(&metadata))
+            {
+                Ok(metadata) => {
+                    file.metadata = Some(metadata);
+                    file.metadata_len = len;
+                    file.stored = modified.unwrap_or(file.stored);
+                }
+                Err(e) => problems.push(format!("unreadable metadata: {}", e)),
+            },
+            name if name == PENDING_FILE => interrupted = true,
+            name if name == COMMITTED_FILE => (),
+            name if name == CACHED_FILE => file.cached = true,
+            name => match name.parse::<usize>() {
+                Ok(chunk_number) => {
+                    file.chunks.insert(chunk_number, len);
+                }
+                Err(_) => problems.push(format!("unexpected file {:?}", name)),
+            },
+        }
+    }
+
+    // Uploads used to be written in place by earlier versions, the chunks
+    // may not match the metadata.
+    if interrupted && file.metadata.take().is_some() {
+        problems.push("upload interrupted before staging was used".to_string());
+    }
+    if let Some(metadata) = &file.metadata {
+        let expected = hash_path(&metadata.resource_name);
+        if expected != hashed_path {
+            problems.push(format!(
+                "metadata of {:?} expected in {:?}",
+                metadata.resource_name, expected
+            ));
+        }
+        if let Some((&last, _)) = file.chunks.range(metadata.chunks..).next_back() {
+            problems.push(format!(
+                "chunk {} beyond the {} chunks of {:?}",
+                last, metadata.chunks, metadata.resource_name
+            ));
+        }
+    }
+    Ok((file, problems))
+}
+
+/// Checks that a checksum can name the directory of a staged upload.
+fn check_checksum(checksum: &str) -> CdnResult<()> {
+    match !checksum.is_empty() && checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Ok(()),
+        false => Err(CdnError::MalformedMetadata(format!(
+            "Checksum {:?} is not hexadecimal",
+            checksum
+        ))),
+    }
+}
+
+/// Reads the server identifier persisted in `chunks_dir`, generating and
+/// storing a new one the first time.
+fn load_or_create_server_id(chunks_dir: &Path) -> CdnResult<String> {
+    let id_path = chunks_dir.join(SERVER_ID_FILE);
+    if let Ok(id) = std::fs::read_to_string(&id_path) {
+        return Ok(id.trim().to_string());
+    }
+    let seed = format!(
+        "{:?}{}{:?}",
+        SystemTime::now(),
+        std::process::id(),
+        chunks_dir
+    );
+    let id = hash_path(&seed)[..32].to_lowercase();
+    std::fs::create_dir_all(chunks_dir)
+        .and_then(|_| std::fs::write(&id_path, &id))
+        .map_err(|e| {
+            CdnError::Io(format!(
+                "Unable to persist server id in {:?} {:?}",
+                id_path, e
+            ))
+        })?;
+    Ok(id)
+}
+
+/// A server discovered through its heartbeats.
+#[derive(Debug, Clone)]
+struct Member {
+    info: ServerInfo,
+    /// Public key signing its heartbeats, if any.
+    identity: Option<String>,
+    last_seen: Instant,
+}
+
+impl Member {
+    /// Whether its last heartbeat is recent enough.
+    fn is_alive(&self) -> bool {
+        self.last_seen.elapsed()
+            < Duration::from_secs(self.info.heartbeat_interval.saturating_mul(3))
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
     pub z: Arc<Zenoh>,
     pub config: ServerConfig,
+    pub id: String,
+    /// Files stored in `chunks_dir`, by hashed path.
+    index: Arc<Mutex<HashMap<String, StoredFile>>>,
+    members: Arc<RwLock<HashMap<String, Member>>>,
+    limiter: Option<Arc<RateLimiter>>,
+    identity: Option<SigningKey>,
+    replay: Arc<ReplayGuard>,
+    started: Instant,
+    errors: Arc<Mutex<VecDeque<RecentError>>>,
+    /// Corrupted chunks kept in `QUARANTINE_DIR`, with their size and when
+    /// they were quarantined.
+    quarantine: Arc<Mutex<HashMap<PathBuf, (u64, SystemTime)>>>,
 }
 
 impl Server {
     pub fn new(z: Arc<Zenoh>, config: ServerConfig) -> Self {
-        Self { z, config }
+        let id = match &config.server_id {
+            Some(id) => id.clone(),
+            None => load_or_create_server_id(&config.chunks_dir).unwrap_or_else(|e| {
+                let id = hash_path(&format!("{:?}", SystemTime::now()))[..32].to_lowercase();
+                log::warn!("{:?}, using the temporary server id {:?}", e, id);
+                id
+            }),
+        };
+        let identity = config.signing_key.as_ref().and_then(|key| {
+            SigningKey::from_hex(key)
+                .map_err(|e| log::warn!("Ignoring the signing key: {}", e))
+                .ok()
+        });
+        Self {
+            z,
+            id,
+            identity,
+            replay: Arc::new(ReplayGuard::default()),
+            index: Arc::new(Mutex::new(HashMap::new())),
+            started: Instant::now(),
+            errors: Arc::new(Mutex::new(VecDeque::new())),
+            quarantine: Arc::new(Mutex::new(HashMap::new())),
+            members: Arc::new(RwLock::new(HashMap::new())),
+            limiter: config
+                .reply_rate_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate))),
+            config,
+        }
     }
 
-    pub fn serve(&self) -> ZResult<JoinHandle<ZResult<()>>> {
+    pub fn serve(&self) -> CdnResult<JoinHandle<CdnResult<()>>> {
+        let sweeper_self = self.clone();
+        async_std::task::spawn(async move { sweeper_self.sweeper().await });
+
+        let heartbeat_self = self.clone();
+        async_std::task::spawn(async move { heartbeat_self.heartbeat().await });
+
+        if let Some(gc) = &self.config.gc {
+            let gc_self = self.clone();
+            let interval = Duration::from_secs(gc.interval);
+            async_std::task::spawn(async move { gc_self.garbage_collector(interval).await });
+        }
+
+        if let Some(scrub) = &self.config.scrub {
+            let scrubber_self = self.clone();
+            let scrub = scrub.clone();
+            async_std::task::spawn(async move { scrubber_self.scrubber(scrub).await });
+        }
+
+        if let Some(interval) = self.config.anti_entropy_interval {
+            let anti_entropy_self = self.clone();
+            async_std::task::spawn(async move {
+                anti_entropy_self
+                    .anti_entropy(Duration::from_secs(interval))
+                    .await
+            });
+        }
+
         let cloned_self = self.clone();
         let handle = async_std::task::spawn(async move { cloned_self.run().await });
         Ok(handle)
     }
 
-    pub async fn run(&self) -> ZResult<()> {
+    pub async fn run(&self) -> CdnResult<()> {
+        self.load_index().await?;
+
         let ws = self.z.workspace(None).await?;
 
         let resource_space = Selector::try_from(self.config.resource_space.clone())?;
@@ -75,6 +461,8 @@ impl Server {
         let mut get_stream = ws
             .register_eval(&PathExpr::try_from(self.config.resource_space.clone())?)
             .await?;
+        let admin_space = format!("{}/**", ADMIN_PATH!(self.root(), self.id));
+        let mut admin_stream = ws.register_eval(&PathExpr::try_from(admin_space)?).await?;
 
         loop {
             select! {
@@ -82,171 +470,1980 @@ impl Server {
 
                     match self.process_sample(sample).await {
                         Ok(_) => (),
-                        Err(e) => log::error!("Process file storage failed: {:?}",e ),
+                        Err(e) => {
+                            let message = format!("Process file storage failed: {:?}", e);
+                            self.record_error(message).await
+                        }
                     }
 
                 }
                 query = get_stream.next().fuse() => {
-                    match self.process_query(query).await {
-                        Ok(_) => (),
-                        Err(e) => log::error!("Process file retrieve failed: {:?}",e ),
-                    }
+                    // Queries may need to query the network, this server included.
+                    let cloned_self = self.clone();
+                    async_std::task::spawn(async move {
+                        match cloned_self.process_query(query).await {
+                            Ok(_) => (),
+                            // Routine misses, not worth keeping in the status.
+                            Err(CdnError::NotFound(e)) => {
+                                log::debug!("Process file retrieve failed: {}", e)
+                            }
+                            Err(e) => {
+                                let message = format!("Process file retrieve failed: {:?}", e);
+                                cloned_self.record_error(message).await
+                            }
+                        }
+                    });
+                }
+                query = admin_stream.next().fuse() => {
+                    let cloned_self = self.clone();
+                    async_std::task::spawn(async move {
+                        match cloned_self.process_admin_query(query).await {
+                            Ok(_) => (),
+                            // Probes of the status, they would flood the errors it shows.
+                            Err(CdnError::Denied(e)) => {
+                                log::warn!("Admin query denied: {}", e)
+                            }
+                            Err(e) => {
+                                let message = format!("Process admin query failed: {:?}", e);
+                                cloned_self.record_error(message).await
+                            }
+                        }
+                    });
                 }
             }
         }
     }
 
-    async fn process_query(&self, query: Option<GetRequest>) -> ZResult<()> {
-        let resource_prefix = format!(
-            "{}/{}",
-            self.config
-                .resource_space
-                .clone()
-                .split("/**")
-                .collect::<Vec<&str>>()[0],
-            FILES_KEY
-        );
+    /// Logs an error and keeps it among the recent errors of the status.
+    async fn record_error(&self, message: String) {
+        log::error!("{}", message);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut errors = self.errors.lock().await;
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError { time, message });
+    }
+
+    /// Status of the server, listing the files `identity` is allowed to
+    /// read.
+    pub async fn status(&self, identity: Option<&str>) -> AdminStatus {
+        let mut config = self.config.clone();
+        config.signing_key = None;
+        let quarantined_bytes = self.quarantined_bytes().await;
+        let mut status = AdminStatus {
+            id: self.id.clone(),
+            uptime: self.started.elapsed().as_secs(),
+            file_count: 0,
+            complete_file_count: 0,
+            total_bytes: quarantined_bytes,
+            quarantined_bytes,
+            free_disk: fs2::available_space(&self.config.chunks_dir).ok(),
+            config,
+            identity: self.identity.as_ref().map(|key| key.public_key()),
+            recent_errors: self.errors.lock().await.iter().cloned().collect(),
+            files: Vec::new(),
+        };
+
+        for (hashed_path, file) in self.index.lock().await.iter() {
+            status.file_count += 1;
+            status.total_bytes += file.size;
+            if file.complete() {
+                status.complete_file_count += 1;
+            }
+            let readable = match file.resource_name() {
+                Some(resource_name) => self
+                    .check_access(Access::Read, resource_name, identity)
+                    .is_ok(),
+                None => self.config.acl.is_empty(),
+            };
+            if readable {
+                status.files.push(FileStatus {
+                    hashed_path: hashed_path.clone(),
+                    resource_name: file.resource_name().map(|name| name.to_string()),
+                    checksum: file.metadata.as_ref().map(|m| m.checksum.clone()),
+                    complete: file.complete(),
+                    cached: file.cached,
+                    chunks: file.metadata.as_ref().map(|m| m.chunks),
+                    stored_chunks: file.chunks.len(),
+                    quarantined_chunks: file.quarantined.iter().cloned().collect(),
+                    uploads: file.staged.len(),
+                    size: file.size,
+                });
+            }
+        }
+        status
+            .files
+            .sort_by(|a, b| a.resource_name.cmp(&b.resource_name));
+        status
+    }
 
+    async fn process_admin_query(&self, query: Option<GetRequest>) -> CdnResult<()> {
         let query = match query {
             Some(s) => Ok(s),
-            None => zerror!(ZErrorKind::Other {
-                descr: "Eval received nothing".to_string()
-            }),
+            None => Err(CdnError::Other("Admin eval received nothing".to_string())),
         }?;
+        let identity = self.replay.verify(
+            query.selector.path_expr.as_str(),
+            &query.selector.properties,
+        )?;
+        log::debug!("Received admin query {:?}", query.selector);
+        self.check_admin(identity.as_deref())?;
 
-        match query.selector.path_expr.is_a_path() {
-            false => zerror!(ZErrorKind::Other {
-                descr: format!("Malformend query {:?}", query.selector)
-            }),
-            _ => Ok(()),
-        }?;
-        let query_path = query.selector.path_expr.as_str();
+        let status = self.status(identity.as_deref()).await;
+        let path = format!("{}/status", ADMIN_PATH!(self.root(), self.id));
+        query
+            .reply_async(ZPath::try_from(path)?, Value::Json(status.serialize()?))
+            .await;
+        Ok(())
+    }
 
-        log::debug!("Received query {:?}", query_path);
-        let complete_path = extract_complete_file_path(&resource_prefix, query_path)?;
+    /// The status discloses the configuration and the recent errors, it is
+    /// only open to anyone on servers without any access control.
+    fn check_admin(&self, identity: Option<&str>) -> CdnResult<()> {
+        let config = &self.config;
+        let allowed = (config.acl.is_empty() && config.admins.is_empty())
+            || config.admins.iter().any(|admin| {
+                admin == "*"
+                    || match identity {
+                        Some(identity) => admin.eq_ignore_ascii_case(identity),
+                        None => false,
+                    }
+            });
+        match allowed {
+            true => Ok(()),
+            false => Err(CdnError::Denied(format!(
+                "Status of server {:?} for {}",
+                self.id,
+                identity.unwrap_or("an unsigned request")
+            ))),
+        }
+    }
 
-        let resp: Value = match extract_chunk_number(&complete_path) {
-            Err(_) => {
-                log::debug!("Getting metadata");
-                let hashed_path = hash_path(&complete_path);
-                let metadata_path = self.config.chunks_dir.join(&hashed_path).join("metadata");
-                log::debug!(
-                    "Getting metadata for {:?} - reading from {:?}",
-                    complete_path,
-                    metadata_path
-                );
-                let metadata = read_file_to_string(&metadata_path).await?;
-                Value::Json(metadata)
+    async fn heartbeat(&self) {
+        let interval = Duration::from_secs(self.config.heartbeat_interval);
+        loop {
+            match self.announce().await {
+                Ok(_) => (),
+                Err(e) => {
+                    self.record_error(format!("Server heartbeat failed: {:?}", e))
+                        .await
+                }
             }
-            Ok(chunk_number) => {
-                log::debug!("Getting chunk");
-                let path = extract_file_path(&resource_prefix, query_path)?;
-                let hashed_path = hash_path(&path);
+            async_std::task::sleep(interval).await;
+        }
+    }
+
+    async fn announce(&self) -> CdnResult<()> {
+        let ws = self.z.workspace(None).await?;
+        let path = ZPath::try_from(SERVER_INFO_PATH!(self.root(), self.id))?;
+        let mut info = self.info().await;
+        if let Some(key) = &self.identity {
+            key.sign_server_info(&mut info)?;
+        }
+        Ok(ws.put(&path, Value::Json(info.serialize()?)).await?)
+    }
+
+    async fn info(&self) -> ServerInfo {
+        ServerInfo {
+            id: self.id.clone(),
+            quota: self.config.quota,
+            used_bytes: self.index.lock().await.values().map(|f| f.size).sum(),
+            heartbeat_interval: self.config.heartbeat_interval,
+            timestamp: 0,
+            signature: None,
+        }
+    }
+
+    /// Heartbeats must be signed when this server signs its queries or has
+    /// access control rules, then by an identity allowed to read under some
+    /// rule, as the servers replicating the files are.
+    fn check_heartbeat(&self, info: &ServerInfo, identity: Option<&str>) -> CdnResult<()> {
+        let config = &self.config;
+        if self.identity.is_none() && config.acl.is_empty() {
+            return Ok(());
+        }
+        let identity = identity.ok_or_else(|| {
+            CdnError::Denied(format!("Unsigned heartbeat of server {:?}", info.id))
+        })?;
+        let allowed = config.acl.is_empty()
+            || config.acl.iter().any(|rule| {
+                rule.read
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(identity))
+            });
+        match allowed {
+            true => Ok(()),
+            false => Err(CdnError::Denied(format!(
+                "Heartbeat of server {:?} signed by {}",
+                info.id, identity
+            ))),
+        }
+    }
+
+    async fn process_heartbeat(&self, sample: Change) -> CdnResult<()> {
+        let signed = self.identity.is_some() || !self.config.acl.is_empty();
+        match (sample.kind, sample.value) {
+            // Deletes cannot be signed, members then leave once silent.
+            (ChangeKind::Delete, _) if signed => Ok(()),
+            (ChangeKind::Delete, _) => {
+                let id = sample.path.last_segment().to_string();
+                log::debug!("Server {:?} left", id);
+                self.members.write().await.remove(&id);
+                Ok(())
+            }
+            (_, Some(Value::Json(value))) => {
+                let mut info = ServerInfo::deserialize(&value)?;
+                if info.id == self.id {
+                    return Ok(());
+                }
+                info.heartbeat_interval = info.heartbeat_interval.min(MAX_HEARTBEAT_INTERVAL);
+                let identity = server_info_signer(&info)?;
+                self.check_heartbeat(&info, identity.as_deref())?;
+
+                let mut members = self.members.write().await;
+                match members.get(&info.id) {
+                    None => log::debug!("Discovered server {:?}", info.id),
+                    // A member keeps its identity as long as it is alive.
+                    Some(member) if member.is_alive() && member.identity != identity => {
+                        return Err(CdnError::Denied(format!(
+                            "Heartbeat of server {:?} signed by {} instead of {}",
+                            info.id,
+                            identity.as_deref().unwrap_or("nobody"),
+                            member.identity.as_deref().unwrap_or("nobody")
+                        )))
+                    }
+                    Some(_) => (),
+                }
+                let member = Member {
+                    info,
+                    identity,
+                    last_seen: Instant::now(),
+                };
+                members.insert(member.info.id.clone(), member);
+                Ok(())
+            }
+            _ => Err(CdnError::MalformedMetadata(format!(
+                "Heartbeat {:?} is not correctly formatted",
+                sample.path
+            ))),
+        }
+    }
+
+    /// Identifiers of the servers whose heartbeat is recent enough, this
+    /// server included.
+    pub async fn alive_members(&self) -> Vec<String> {
+        let mut alive = self
+            .members
+            .read()
+            .await
+            .values()
+            .filter(|m| m.is_alive())
+            .map(|m| m.info.id.clone())
+            .collect::<Vec<String>>();
+        if !alive.contains(&self.id) {
+            alive.push(self.id.clone());
+        }
+        alive.sort();
+        alive
+    }
+
+    async fn sweeper(&self) {
+        let interval = Duration::from_secs(self.config.retention.sweep_interval);
+        loop {
+            async_std::task::sleep(interval).await;
+            match self.sweep().await {
+                Ok(_) => (),
+                Err(e) => {
+                    self.record_error(format!("Retention sweep failed: {:?}", e))
+                        .await
+                }
+            }
+        }
+    }
+
+    /// Deletes the files whose TTL or the configured maximum age expired,
+    /// then the oldest files until the storage fits `max_total_bytes`.
+    async fn sweep(&self) -> CdnResult<()> {
+        let retention = &self.config.retention;
+        let now = SystemTime::now();
+        let (mut total_bytes, files) = {
+            let index = self.index.lock().await;
+            let files = index
+                .iter()
+                .filter_map(|(hashed_path, file)| {
+                    // Files without metadata are still being uploaded.
+                    let metadata = file.metadata.as_ref()?;
+                    let age = metadata.age(now).unwrap_or_else(|| {
+                        now.duration_since(file.stored)
+                            .unwrap_or_default()
+                            .as_secs()
+                    });
+                    Some((
+                        age,
+                        file.size,
+                        hashed_path.clone(),
+                        metadata.resource_name.clone(),
+                        metadata.ttl,
+                    ))
+                })
+                .collect::<Vec<_>>();
+            (index.values().map(|f| f.size).sum::<u64>(), files)
+        };
+        let mut kept = Vec::new();
+
+        for (age, size, hashed_path, resource_name, ttl) in files {
+            let max_age = match (ttl, retention.max_age) {
+                (Some(ttl), Some(max_age)) => Some(ttl.min(max_age)),
+                (ttl, max_age) => ttl.or(max_age),
+            };
+            match max_age {
+                Some(max_age) if age >= max_age => {
+                    log::debug!(
+                        "Removing expired file {:?} stored in {:?}",
+                        resource_name,
+                        hashed_path
+                    );
+                    self.remove_stored_file(&hashed_path).await?;
+                    total_bytes -= size;
+                }
+                _ => kept.push((age, size, hashed_path, resource_name)),
+            }
+        }
 
-                let chunk_path = self
-                    .config
-                    .chunks_dir
-                    .join(&hashed_path)
-                    .join(&format!("{}", chunk_number));
+        if let Some(max_total_bytes) = retention.max_total_bytes {
+            kept.sort_by_key(|(age, _, _, _)| std::cmp::Reverse(*age));
+            for (_, size, hashed_path, resource_name) in kept {
+                if total_bytes <= max_total_bytes {
+                    break;
+                }
                 log::debug!(
-                    "Getting chunk {:?} for {:?} - reading from {:?}",
-                    chunk_number,
-                    path,
-                    chunk_path
+                    "Removing file {:?} stored in {:?} to free {} bytes",
+                    resource_name,
+                    hashed_path,
+                    size
                 );
-                let data = read_file_to_vec(&chunk_path).await?;
-                data.into()
+                self.remove_stored_file(&hashed_path).await?;
+                total_bytes -= size;
             }
-        };
+        }
 
-        query.reply_async(ZPath::try_from(query_path)?, resp).await;
         Ok(())
     }
 
-    async fn process_sample(&self, sample: Option<Change>) -> ZResult<()> {
-        let resource_prefix = format!(
-            "{}/{}",
-            self.config
-                .resource_space
-                .clone()
-                .split("/**")
-                .collect::<Vec<&str>>()[0],
-            FILES_KEY
-        );
+    async fn garbage_collector(&self, interval: Duration) {
+        let dry_run = self
+            .config
+            .gc
+            .as_ref()
+            .map(|gc| gc.dry_run)
+            .unwrap_or(false);
+        loop {
+            async_std::task::sleep(interval).await;
+            match self.collect_garbage(dry_run).await {
+                Ok(report)
+                    if dry_run && (!report.uploads.is_empty() || report.quarantined_chunks > 0) =>
+                {
+                    log::info!(
+                        "Garbage collection would remove {} partial uploads and {} \
+                         quarantined chunks, {} bytes: {:?}",
+                        report.uploads.len(),
+                        report.quarantined_chunks,
+                        report.bytes,
+                        report.uploads
+                    )
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    self.record_error(format!("Garbage collection failed: {:?}", e))
+                        .await
+                }
+            }
+        }
+    }
 
-        let sample = match sample {
-            Some(s) => Ok(s),
-            None => zerror!(ZErrorKind::Other {
-                descr: "Subscriber received nothing".to_string(),
-            }),
-        }?;
-        log::debug!("Received data from {:?}", sample.path);
-        match sample.kind {
-            ChangeKind::Put | ChangeKind::Patch => {
-                let value = sample.value.ok_or_else(|| {
-                    zerror2!(ZErrorKind::Other {
-                        descr: "Sample is missing value".to_string(),
+    /// Removes the partial uploads untouched for the grace period: uploads
+    /// that were never committed or completed, and chunks left without
+    /// metadata by earlier versions. The quarantined chunks are purged after
+    /// `quarantine_max_age`. With `dry_run` they are only reported.
+    pub async fn collect_garbage(&self, dry_run: bool) -> CdnResult<GcReport> {
+        let config = self.config.gc.clone().unwrap_or_default();
+        let grace_period = config.grace_period;
+        let now = SystemTime::now();
+        let mut report = GcReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        let idle = |since: SystemTime| now.duration_since(since).unwrap_or_default().as_secs();
+        let (abandoned, staged) = {
+            let index = self.index.lock().await;
+            let abandoned = index
+                .iter()
+                .filter(|(_, file)| file.metadata.is_none() && file.staged.is_empty())
+                .map(|(hashed_path, file)| (hashed_path.clone(), idle(file.last_access), file.size))
+                .collect::<Vec<_>>();
+            let staged = index
+                .iter()
+                .flat_map(|(hashed_path, file)| {
+                    file.staged.iter().map(move |(checksum, upload)| {
+                        (hashed_path.clone(), checksum.clone(), upload.clone())
                     })
-                })?;
+                })
+                .collect::<Vec<_>>();
+            (abandoned, staged)
+        };
 
-                match value {
-                    Value::Raw(_, buf) => {
-                        let data = buf.to_vec();
-                        log::debug!("Received {:?} bytes", data.len());
+        for (hashed_path, idle, size) in abandoned {
+            if idle < grace_period {
+                continue;
+            }
+            let upload = PartialUpload {
+                hashed_path: hashed_path.clone(),
+                resource_name: None,
+                checksum: None,
+                missing_chunks: Vec::new(),
+                size,
+                idle,
+            };
+            if !dry_run {
+                log::info!("Removing abandoned partial upload {:?}", upload);
+                self.remove_stored_file(&hashed_path).await?;
+            }
+            report.bytes += upload.size;
+            report.uploads.push(upload);
+        }
 
-                        let path = extract_file_path(&resource_prefix, sample.path.as_str())?;
-                        let chunk_number = extract_chunk_number(sample.path.as_str())?;
-                        let hashed_path = hash_path(&path);
+        for (hashed_path, checksum, staged) in staged {
+            let idle = idle(staged.touched);
+            if idle < grace_period {
+                continue;
+            }
+            let metadata = staged.metadata;
+            let mut missing_chunks = Vec::new();
+            for i in 0..metadata.chunks {
+                if !staged.chunks.contains_key(&i)
+                    && self.owns_chunk(&metadata.resource_name, i).await
+                {
+                    missing_chunks.push(i);
+                }
+            }
 
-                        let complete_path = self.config.chunks_dir.join(&hashed_path);
+            let upload = PartialUpload {
+                hashed_path: hashed_path.clone(),
+                resource_name: Some(metadata.resource_name),
+                checksum: Some(checksum.clone()),
+                missing_chunks,
+                size: staged.size,
+                idle,
+            };
+            if !dry_run {
+                log::info!("Removing abandoned partial upload {:?}", upload);
+                self.remove_upload(&hashed_path, &checksum).await?;
+            }
+            report.bytes += upload.size;
+            report.uploads.push(upload);
+        }
 
-                        log::debug!(
-                            "Received {:?} Chunk {:?} - Hashed {:?} - Going to be stored in {:?}",
-                            path,
-                            chunk_number,
-                            hashed_path,
-                            complete_path
-                        );
+        let expired = self
+            .quarantine
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, (_, quarantined))| idle(*quarantined) >= config.quarantine_max_age)
+            .map(|(path, (size, _))| (path.clone(), *size))
+            .collect::<Vec<_>>();
+        for (path, size) in expired {
+            if !dry_run {
+                log::info!("Removing quarantined chunk {:?}", path);
+                remove_file_if_exists(&path).await?;
+                self.quarantine.lock().await.remove(&path);
+                // The folder of the file is left once empty.
+                if let Some(dir) = path.parent() {
+                    let _ = async_std::fs::remove_dir(dir).await;
+                }
+            }
+            report.bytes += size;
+            report.quarantined_chunks += 1;
+        }
+        Ok(report)
+    }
 
-                        create_dir_if_not_exists(&complete_path).await?;
+    async fn scrubber(&self, config: ScrubConfig) {
+        let limiter = RateLimiter::new(config.rate);
+        let interval = Duration::from_secs(config.interval);
+        loop {
+            // The index is loaded by `run` meanwhile.
+            async_std::task::sleep(interval).await;
+            match self.scrub(&limiter, config.refetch).await {
+                Ok(0) => (),
+                Ok(corrupted) => log::warn!("Scrubbing found {} corrupted chunks", corrupted),
+                Err(e) => {
+                    self.record_error(format!("Scrubbing failed: {:?}", e))
+                        .await
+                }
+            }
+        }
+    }
 
-                        let chunk_path = complete_path.join(&format!("{}", chunk_number));
+    /// Checks the chunks of the complete files against the digests of their
+    /// metadata, reading at most `limiter` bytes per second. Returns the
+    /// number of corrupted chunks, which are quarantined and retrieved again
+    /// from the other servers with `refetch`.
+    async fn scrub(&self, limiter: &RateLimiter, refetch: bool) -> CdnResult<usize> {
+        let files = self
+            .index
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, file)| file.complete())
+            .filter_map(|(hashed_path, file)| match &file.metadata {
+                Some(metadata) if !metadata.digests.is_empty() => {
+                    Some((hashed_path.clone(), metadata.clone(), file.chunks.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
-                        log::debug!(
-                            "Received {:?} Chunk {:?} - Hashed {:?} - Going to be stored in {:?}",
-                            path,
-                            chunk_number,
-                            hashed_path,
-                            chunk_path
-                        );
-                        Ok(write_chunk_file(&chunk_path, &data).await?)
+        let mut corrupted = 0;
+        for (hashed_path, metadata, chunks) in files {
+            for (chunk_number, size) in chunks {
+                limiter.acquire(size as usize).await;
+                // The file may have been removed or replaced meanwhile.
+                let data = match self.read_chunk(&hashed_path, chunk_number).await {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+                if self.current_checksum(&hashed_path).await.as_ref() != Some(&metadata.checksum) {
+                    break;
+                }
+                if let Err(e) = metadata.validate_chunk(chunk_number, &data) {
+                    log::warn!("Scrubbing found a corrupted chunk: {}", e);
+                    corrupted += 1;
+                    self.quarantine_chunk(&hashed_path, chunk_number).await?;
+                    if refetch {
+                        if let Err(e) = self.refetch_chunk(&metadata, chunk_number).await {
+                            log::warn!(
+                                "Unable to retrieve chunk {} of {:?} again: {:?}",
+                                chunk_number,
+                                metadata.resource_name,
+                                e
+                            );
+                        }
                     }
-                    Value::Json(value) => {
-                        let metadata = FileMetadata::deserialize(&value)?;
-                        let path = metadata.resource_name.clone();
-                        let hashed_path = hash_path(&path);
-                        let metadata_path =
-                            self.config.chunks_dir.join(&hashed_path).join("metadata");
+                }
+            }
+        }
+        Ok(corrupted)
+    }
 
-                        log::debug!(
-                            "Received Metadata {:?} - Going to be stored in {:?}",
-                            metadata,
-                            metadata_path
-                        );
+    /// Moves a corrupted chunk to `QUARANTINE_DIR`, named after the chunk
+    /// number and the second it was quarantined. The file is still served,
+    /// the chunk is missing until stored again.
+    async fn quarantine_chunk(&self, hashed_path: &str, chunk_number: usize) -> CdnResult<()> {
+        let complete_path = self.config.chunks_dir.join(hashed_path);
+        let quarantine_path = self
+            .config
+            .chunks_dir
+            .join(QUARANTINE_DIR)
+            .join(hashed_path);
+        create_dir_if_not_exists(&quarantine_path).await?;
+        let now = SystemTime::now();
+        let quarantined = quarantine_path.join(format!(
+            "{}.{}",
+            chunk_number,
+            now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+        ));
+        rename_file(
+            &complete_path.join(format!("{}", chunk_number)),
+            &quarantined,
+        )
+        .await?;
+        let size = self
+            .update_index(hashed_path, |file| {
+                let size = file.chunks.get(&chunk_number).cloned().unwrap_or(0);
+                file.remove_chunk(chunk_number);
+                file.quarantined.insert(chunk_number);
+                size
+            })
+            .await;
+        self.quarantine
+            .lock()
+            .await
+            .insert(quarantined, (size, now));
+        Ok(())
+    }
 
-                        Ok(write_metadata_file(&metadata_path, &value).await?)
-                    }
-                    _ => {
-                        log::error!("Subscriber received data not correctly formatted");
-                        Ok(())
-                    }
+    async fn quarantined_bytes(&self) -> u64 {
+        self.quarantine
+            .lock()
+            .await
+            .values()
+            .map(|(size, _)| size)
+            .sum()
+    }
+
+    /// Finds the chunks quarantined before the server started. Those
+    /// quarantined by earlier versions are aged from their last
+    /// modification.
+    async fn load_quarantine(&self) -> CdnResult<()> {
+        let quarantine_dir = self.config.chunks_dir.join(QUARANTINE_DIR);
+        let mut quarantine = HashMap::new();
+        if path_exists(&quarantine_dir).await {
+            for dir in list_dirs(&quarantine_dir).await? {
+                for path in list_files(&dir).await? {
+                    let size = async_std::fs::metadata(&path)
+                        .await
+                        .map(|m| m.len())
+                        .unwrap_or(0);
+                    let quarantined = match path
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .and_then(|ext| ext.parse().ok())
+                    {
+                        Some(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+                        None => modified_time(&path).await?,
+                    };
+                    quarantine.insert(path, (size, quarantined));
                 }
             }
-            ChangeKind::Delete => {
-                //We should delete the chunk in this case.
-                log::trace!("We should delete the chunk");
-                Ok(())
+        }
+        *self.quarantine.lock().await = quarantine;
+        Ok(())
+    }
+
+    /// Retrieves a chunk of the file described by `metadata` from the other
+    /// servers.
+    async fn refetch_chunk(&self, metadata: &FileMetadata, chunk_number: usize) -> CdnResult<()> {
+        let path = &metadata.resource_name;
+        let chunk_path = FILE_CHUNK_PATH!(self.root(), path, chunk_number);
+        match self
+            .fetch_remote(&chunk_path, &[(CHECKSUM_PROPERTY, &metadata.checksum)])
+            .await?
+        {
+            Value::Raw(_, buf) => {
+                let data = buf.to_vec();
+                metadata.validate_chunk(chunk_number, &data)?;
+                self.store_chunk(path, None, chunk_number, &data).await
             }
+            _ => Err(CdnError::Other(format!(
+                "Chunk {:?} is stale or not correctly formatted",
+                chunk_path
+            ))),
         }
     }
+
+    /// Directories of the files stored in `chunks_dir`, with their hashed
+    /// path.
+    async fn stored_dirs(&self) -> CdnResult<Vec<(String, std::path::PathBuf)>> {
+        let mut dirs = Vec::new();
+        for dir in list_dirs(&self.config.chunks_dir).await? {
+            if let Some(name) = dir.file_name().and_then(|name| name.to_str()) {
+                if is_hashed_path(name) {
+                    dirs.push((name.to_string(), dir.clone()));
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Directories of the uploads staged in `STAGING_DIR`, with the hashed
+    /// path and checksum of their file.
+    async fn staged_dirs(&self) -> CdnResult<Vec<(String, String, PathBuf)>> {
+        let staging_dir = self.config.chunks_dir.join(STAGING_DIR);
+        let mut dirs = Vec::new();
+        if !path_exists(&staging_dir).await {
+            return Ok(dirs);
+        }
+        for file_dir in list_dirs(&staging_dir).await? {
+            let hashed_path = match file_dir.file_name().and_then(|name| name.to_str()) {
+                Some(name) if is_hashed_path(name) => name.to_string(),
+                _ => continue,
+            };
+            for dir in list_dirs(&file_dir).await? {
+                if let Some(checksum) = dir.file_name().and_then(|name| name.to_str()) {
+                    if check_checksum(checksum).is_err() {
+                        continue;
+                    }
+                    dirs.push((hashed_path.clone(), checksum.to_string(), dir.clone()));
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    fn file_dir(&self, hashed_path: &str) -> PathBuf {
+        self.config.chunks_dir.join(hashed_path)
+    }
+
+    fn staging_dir(&self, hashed_path: &str, checksum: &str) -> PathBuf {
+        self.config
+            .chunks_dir
+            .join(STAGING_DIR)
+            .join(hashed_path)
+            .join(checksum)
+    }
+
+    /// Builds the index from the files already present in `chunks_dir`,
+    /// reporting the inconsistencies found.
+    async fn load_index(&self) -> CdnResult<()> {
+        create_dir_if_not_exists(&self.config.chunks_dir).await?;
+        for dir in list_dirs(&self.config.chunks_dir).await? {
+            match dir.file_name().and_then(|name| name.to_str()) {
+                Some(name)
+                    if is_hashed_path(name) || name == QUARANTINE_DIR || name == STAGING_DIR => {}
+                _ => log::warn!("Ignoring {:?}, not a stored file", dir),
+            }
+        }
+
+        let mut index = HashMap::new();
+        let mut inconsistencies = 0;
+        for (hashed_path, dir) in self.stored_dirs().await? {
+            let (file, mut problems) = match scan_file(&dir, &hashed_path).await {
+                Ok(scan) => scan,
+                Err(e) => {
+                    log::warn!("Unable to index {:?}: {}", dir, e);
+                    inconsistencies += 1;
+                    continue;
+                }
+            };
+            // Without replication every chunk of a complete file is stored.
+            if let (Some(metadata), true) = (&file.metadata, self.config.replication.is_none()) {
+                let missing = (0..metadata.chunks)
+                    .filter(|i| !file.chunks.contains_key(i))
+                    .count();
+                if file.complete() && missing > 0 {
+                    problems.push(format!(
+                        "{} chunks of the complete file {:?} are missing",
+                        missing, metadata.resource_name
+                    ));
+                }
+            }
+            for problem in &problems {
+                log::warn!("Inconsistency in {:?}: {}", dir, problem);
+            }
+            inconsistencies += problems.len();
+            index.insert(hashed_path, file);
+        }
+
+        let mut committed = Vec::new();
+        for (hashed_path, checksum, dir) in self.staged_dirs().await? {
+            let (staged, mut problems) = match scan_file(&dir, &hashed_path).await {
+                Ok(scan) => scan,
+                Err(e) => {
+                    log::warn!("Unable to index {:?}: {}", dir, e);
+                    inconsistencies += 1;
+                    continue;
+                }
+            };
+            match staged.metadata {
+                Some(metadata) if metadata.checksum == checksum => {
+                    let upload = StagedUpload {
+                        metadata_len: staged.metadata_len,
+                        chunks: staged.chunks,
+                        size: staged.size,
+                        committed: path_exists(&dir.join(COMMITTED_FILE)).await,
+                        started: staged.stored,
+                        touched: staged.last_access,
+                        metadata,
+                    };
+                    if upload.committed {
+                        committed.push((upload.metadata.resource_name.clone(), checksum.clone()));
+                    }
+                    let file = index.entry(hashed_path).or_insert_with(StoredFile::new);
+                    file.size += upload.size;
+                    file.staged.insert(checksum, upload);
+                }
+                // It can never complete, the client announces it again.
+                _ => {
+                    problems.push("staged upload without its metadata, removed".to_string());
+                    remove_dir_if_exists(&dir).await?;
+                }
+            }
+            for problem in &problems {
+                log::warn!("Inconsistency in {:?}: {}", dir, problem);
+            }
+            inconsistencies += problems.len();
+        }
+
+        log::info!(
+            "Indexed {} stored files, {} complete, {} bytes, {} inconsistencies",
+            index.len(),
+            index.values().filter(|f| f.complete()).count(),
+            index.values().map(|f| f.size).sum::<u64>(),
+            inconsistencies
+        );
+        *self.index.lock().await = index;
+        self.load_quarantine().await?;
+
+        // The server may have stopped before swapping a complete upload in.
+        for (path, checksum) in committed {
+            if let Err(e) = self.try_complete(&path, &checksum).await {
+                log::warn!("Unable to complete the upload of {:?}: {:?}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn touch(&self, hashed_path: &str) {
+        if let Some(file) = self.index.lock().await.get_mut(hashed_path) {
+            file.last_access = SystemTime::now();
+        }
+    }
+
+    /// Applies a change of the file stored under `hashed_path` to its index
+    /// entry, created if needed.
+    async fn update_index<R, F: FnOnce(&mut StoredFile) -> R>(
+        &self,
+        hashed_path: &str,
+        change: F,
+    ) -> R {
+        let mut index = self.index.lock().await;
+        let file = index
+            .entry(hashed_path.to_string())
+            .or_insert_with(StoredFile::new);
+        let result = change(file);
+        file.last_access = SystemTime::now();
+        result
+    }
+
+    /// Whether the generation served for `hashed_path` was retrieved by the
+    /// pull-through cache.
+    async fn is_cached(&self, hashed_path: &str) -> bool {
+        match self.index.lock().await.get(hashed_path) {
+            Some(file) => file.cached,
+            None => false,
+        }
+    }
+
+    /// Metadata of the generation served for `hashed_path`.
+    async fn stored_metadata(&self, hashed_path: &str) -> Option<FileMetadata> {
+        self.index
+            .lock()
+            .await
+            .get(hashed_path)
+            .and_then(|file| file.metadata.clone())
+    }
+
+    /// Where chunk `chunk_number` of the generation `checksum` of a file is
+    /// stored, the generation served when `None`.
+    async fn locate_chunk(
+        &self,
+        hashed_path: &str,
+        chunk_number: usize,
+        checksum: Option<&str>,
+    ) -> ChunkLocation {
+        let index = self.index.lock().await;
+        let file = match index.get(hashed_path) {
+            Some(file) => file,
+            None => return ChunkLocation::Missing,
+        };
+        let served = file.metadata.as_ref();
+        match checksum {
+            Some(checksum) if Some(checksum) != served.map(|m| m.checksum.as_str()) => match file
+                .staged
+                .get(checksum)
+            {
+                Some(upload) if upload.chunks.contains_key(&chunk_number) => ChunkLocation::Stored(
+                    self.staging_dir(hashed_path, checksum)
+                        .join(format!("{}", chunk_number)),
+                ),
+                Some(_) => ChunkLocation::Missing,
+                None => ChunkLocation::Stale(served.map(|m| (m.checksum.clone(), m.generation))),
+            },
+            _ if file.chunks.contains_key(&chunk_number) => {
+                ChunkLocation::Stored(self.file_dir(hashed_path).join(format!("{}", chunk_number)))
+            }
+            _ => ChunkLocation::Missing,
+        }
+    }
+
+    /// Reads a chunk of the generation served, answering right away when
+    /// the index does not know it.
+    async fn read_chunk(&self, hashed_path: &str, chunk_number: usize) -> CdnResult<Vec<u8>> {
+        match self.locate_chunk(hashed_path, chunk_number, None).await {
+            ChunkLocation::Stored(chunk_path) => read_file_to_vec(&chunk_path).await,
+            _ => Err(CdnError::NotFound(format!(
+                "Chunk {} of {:?} is not stored",
+                chunk_number, hashed_path
+            ))),
+        }
+    }
+
+    /// Removes a file, the uploads in progress included.
+    async fn remove_stored_file(&self, hashed_path: &str) -> CdnResult<()> {
+        remove_dir_if_exists(&self.file_dir(hashed_path)).await?;
+        remove_dir_if_exists(&self.config.chunks_dir.join(STAGING_DIR).join(hashed_path)).await?;
+        self.index.lock().await.remove(hashed_path);
+        Ok(())
+    }
+
+    /// Makes room for `len` more bytes of the generation `checksum` of the
+    /// file stored under `hashed_path`, evicting the least recently used
+    /// complete or cached files. The quarantined chunks count against the
+    /// quota until purged. The generation served is not counted when `checksum`
+    /// replaces it.
+    async fn reserve(&self, hashed_path: &str, checksum: Option<&str>, len: u64) -> CdnResult<()> {
+        let quota = match self.config.quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        loop {
+            let quarantined = self.quarantined_bytes().await;
+            let victim = {
+                let index = self.index.lock().await;
+                let (current, superseded) = index
+                    .get(hashed_path)
+                    .map(|f| (f.size, f.superseded_size(checksum)))
+                    .unwrap_or((0, 0));
+                if current - superseded + len > quota {
+                    return Err(CdnError::QuotaExceeded(format!(
+                        "File stored in {:?} exceeds the quota of {} bytes",
+                        hashed_path, quota
+                    )));
+                }
+                let total: u64 = index.values().map(|f| f.size).sum::<u64>() + quarantined;
+                if total - superseded + len <= quota {
+                    return Ok(());
+                }
+                index
+                    .iter()
+                    .filter(|(k, f)| f.metadata.is_some() && k.as_str() != hashed_path)
+                    .min_by_key(|(_, f)| f.last_access)
+                    .map(|(k, _)| k.clone())
+            };
+
+            match victim {
+                Some(victim) => {
+                    log::debug!("Evicting {:?} to stay within the quota", victim);
+                    self.remove_stored_file(&victim).await?;
+                }
+                None => {
+                    return Err(CdnError::QuotaExceeded(format!(
+                        "Not enough space within the quota of {} bytes for {:?}",
+                        quota, hashed_path
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn anti_entropy(&self, interval: Duration) {
+        loop {
+            async_std::task::sleep(interval).await;
+            match self.synchronize().await {
+                Ok(_) => (),
+                Err(e) => {
+                    self.record_error(format!(
+                        "Synchronization with other servers failed: {:?}",
+                        e
+                    ))
+                    .await
+                }
+            }
+        }
+    }
+
+    /// Lists the files held by the other servers and retrieves the chunks
+    /// missing locally.
+    async fn synchronize(&self) -> CdnResult<()> {
+        let selector = signed_selector(
+            self.identity.as_ref(),
+            &LIST_FILE_PATH!(self.root()),
+            &[(FETCH_PROPERTY, "true")],
+        )?;
+        let ws = self.z.workspace(None).await?;
+        let data = ws.get(&selector).await?.collect::<Vec<zenoh::Data>>().await;
+
+        for kv in data {
+            let value = match kv.value {
+                Value::Json(value) => value,
+                _ => continue,
+            };
+            let metadata = match FileMetadata::deserialize(&value) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    log::warn!("Ignoring listing of {:?}: {:?}", kv.path, e);
+                    continue;
+                }
+            };
+            match self.synchronize_file(&metadata, &value).await {
+                Ok(_) => (),
+                Err(e) => log::warn!(
+                    "Unable to synchronize {:?}: {:?}",
+                    metadata.resource_name,
+                    e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    async fn synchronize_file(&self, metadata: &FileMetadata, value: &str) -> CdnResult<()> {
+        let path = &metadata.resource_name;
+        let hashed_path = hash_path(path);
+        if metadata.expired(SystemTime::now()) {
+            log::trace!("Ignoring the expired file {:?}", path);
+            return Ok(());
+        }
+
+        // A newer generation of the file is staged like an upload, then
+        // swapped in once all its chunks are retrieved. Older generations
+        // still listed by lagging servers are ignored.
+        let generation = match self.stored_metadata(&hashed_path).await {
+            Some(served) if served.checksum == metadata.checksum => None,
+            Some(served) if !metadata.supersedes(&served) => {
+                log::trace!("Ignoring an older generation of {:?}", path);
+                return Ok(());
+            }
+            _ => {
+                self.begin_upload(value).await?;
+                Some(metadata.checksum.as_str())
+            }
+        };
+
+        let mut missing = Vec::new();
+        for i in 0..metadata.chunks {
+            if !self.owns_chunk(path, i).await {
+                continue;
+            }
+            match self.locate_chunk(&hashed_path, i, generation).await {
+                ChunkLocation::Stored(_) => (),
+                _ => missing.push(i),
+            }
+        }
+
+        if !missing.is_empty() {
+            log::debug!("Retrieving {} missing chunks of {:?}", missing.len(), path);
+        }
+        for i in missing {
+            let chunk_path = FILE_CHUNK_PATH!(self.root(), path, i);
+            match self
+                .fetch_remote(&chunk_path, &[(CHECKSUM_PROPERTY, &metadata.checksum)])
+                .await?
+            {
+                Value::Raw(_, buf) => {
+                    let data = buf.to_vec();
+                    metadata.validate_chunk(i, &data)?;
+                    self.store_chunk(path, generation, i, &data).await?
+                }
+                _ => {
+                    return Err(CdnError::Other(format!(
+                        "Chunk {:?} is stale or not correctly formatted",
+                        chunk_path
+                    )))
+                }
+            }
+        }
+        match generation {
+            Some(checksum) => self.commit_upload(path, checksum).await?,
+            // Once its chunks are retrieved, a cached file is complete.
+            None if self.is_cached(&hashed_path).await => {
+                remove_file_if_exists(&self.file_dir(&hashed_path).join(CACHED_FILE)).await?;
+                self.update_index(&hashed_path, |file| file.cached = false)
+                    .await;
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Checks the access control rules, `identity` is `None` for unsigned
+    /// requests.
+    fn check_access(
+        &self,
+        access: Access,
+        resource_name: &str,
+        identity: Option<&str>,
+    ) -> CdnResult<()> {
+        if self.config.acl.is_empty() {
+            return Ok(());
+        }
+        let allowed = match self.acl_rule(resource_name) {
+            Some(rule) => rule.identities(access).iter().any(|allowed| {
+                allowed == "*"
+                    || match identity {
+                        Some(identity) => allowed.eq_ignore_ascii_case(identity),
+                        None => false,
+                    }
+            }),
+            None => false,
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(CdnError::Denied(format!(
+                "{:?} of {:?} by {}",
+                access,
+                resource_name,
+                identity.unwrap_or("an unsigned request")
+            ))),
+        }
+    }
+
+    /// Deletes are not signed, they are allowed by the `delete` flag of the
+    /// rule matching `resource_name`.
+    fn check_delete(&self, resource_name: &str) -> CdnResult<()> {
+        let allowed = match self.acl_rule(resource_name) {
+            Some(rule) => rule.delete,
+            None => self.config.acl.is_empty(),
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(CdnError::Denied(format!("Delete of {:?}", resource_name))),
+        }
+    }
+
+    fn acl_rule(&self, resource_name: &str) -> Option<&AclRule> {
+        acl_rule(&self.config.acl, resource_name)
+    }
+
+    /// Replies with the metadata of every complete file stored locally.
+    async fn reply_list(&self, query: &GetRequest, identity: Option<&str>) -> CdnResult<()> {
+        let complete = self
+            .index
+            .lock()
+            .await
+            .values()
+            .filter(|file| file.complete())
+            .filter_map(|file| file.metadata.clone())
+            .collect::<Vec<FileMetadata>>();
+        for metadata in complete {
+            let resource_name = &metadata.resource_name;
+            if let Err(e) = self.check_access(Access::Read, resource_name, identity) {
+                log::debug!("Not listing {:?}: {}", resource_name, e);
+                continue;
+            }
+            let path = ZPath::try_from(FILE_METADATA_PATH!(self.root(), resource_name))?;
+            query
+                .reply_async(path, Value::Json(metadata.serialize()?))
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Whether this server is one of the replicas of the given chunk, always
+    /// true when replication is not configured.
+    async fn owns_chunk(&self, path: &str, chunk_number: usize) -> bool {
+        match &self.config.replication {
+            Some(replication) => {
+                let members = match replication.members.is_empty() {
+                    true => self.alive_members().await,
+                    false => replication.members.clone(),
+                };
+                let key = format!("{}{}{}", path, SEPARATOR, chunk_number);
+                rendezvous_owners(&key, &members, replication.factor).contains(&&self.id)
+            }
+            None => true,
+        }
+    }
+
+    fn root(&self) -> String {
+        self.config
+            .resource_space
+            .clone()
+            .split("/**")
+            .collect::<Vec<&str>>()[0]
+            .to_string()
+    }
+
+    fn resource_prefix(&self) -> String {
+        format!("{}/{}", self.root(), FILES_KEY)
+    }
+
+    async fn process_query(&self, query: Option<GetRequest>) -> CdnResult<()> {
+        let query = match query {
+            Some(s) => Ok(s),
+            None => Err(CdnError::Other("Eval received nothing".to_string())),
+        }?;
+        // Admin queries are answered by `process_admin_query`.
+        if query
+            .selector
+            .path_expr
+            .as_str()
+            .starts_with(&format!("{}/{}/", self.root(), ADMIN_KEY))
+        {
+            return Ok(());
+        }
+        let identity = self.replay.verify(
+            query.selector.path_expr.as_str(),
+            &query.selector.properties,
+        )?;
+
+        if query.selector.path_expr.as_str() == LIST_FILE_PATH!(self.root()) {
+            log::debug!("Received list query");
+            return self.reply_list(&query, identity.as_deref()).await;
+        }
+
+        let info_path = SERVER_INFO_PATH!(self.root(), self.id);
+        if query.selector.path_expr.as_str() == LIST_SERVERS_PATH!(self.root())
+            || query.selector.path_expr.as_str() == info_path
+        {
+            let info = self.info().await.serialize()?;
+            query
+                .reply_async(ZPath::try_from(info_path)?, Value::Json(info))
+                .await;
+            return Ok(());
+        }
+
+        match query.selector.path_expr.is_a_path() {
+            false => Err(CdnError::Other(format!(
+                "Malformend query {:?}",
+                query.selector
+            ))),
+            _ => Ok(()),
+        }?;
+        let query_path = query.selector.path_expr.as_str();
+        let properties = &query.selector.properties;
+
+        if let Some(server) = properties.get(SERVER_PROPERTY) {
+            if *server != self.id {
+                log::trace!("Query {:?} is for server {:?}", query_path, server);
+                return Ok(());
+            }
+        }
+
+        if let Ok(resource_name) = extract_complete_file_path(&self.resource_prefix(), query_path) {
+            self.check_access(Access::Read, &resource_name, identity.as_deref())?;
+        }
+
+        log::debug!("Received query {:?}", query_path);
+        let resp = match self.local_value(query_path, properties).await {
+            Ok(resp) => resp,
+            // Queries from other caches are answered only from local storage,
+            // otherwise a miss would bounce between cache servers.
+            Err(e) if self.config.cache && !properties.contains_key(FETCH_PROPERTY) => {
+                log::debug!("Cache miss for {:?}: {:?}", query_path, e);
+                self.pull_through(query_path, properties).await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let (Some(limiter), Value::Raw(_, buf)) = (&self.limiter, &resp) {
+            limiter.acquire(buf.len()).await;
+        }
+        query.reply_async(ZPath::try_from(query_path)?, resp).await;
+        Ok(())
+    }
+
+    /// Answers a query for metadata or a chunk from the local storage.
+    async fn local_value(&self, query_path: &str, properties: &Properties) -> CdnResult<Value> {
+        let resource_prefix = self.resource_prefix();
+        let complete_path = extract_complete_file_path(&resource_prefix, query_path)?;
+
+        let resp: Value = match extract_chunk_number(&complete_path) {
+            Err(_) => {
+                log::debug!("Getting metadata");
+                let hashed_path = hash_path(&complete_path);
+                log::debug!(
+                    "Getting metadata for {:?} - stored in {:?}",
+                    complete_path,
+                    hashed_path
+                );
+                // Files being uploaded are only advertised once complete, and
+                // cached ones only to clients.
+                let fetch = properties.contains_key(FETCH_PROPERTY);
+                let metadata = match self.index.lock().await.get(&hashed_path) {
+                    Some(file) if file.complete() || (file.cached && !fetch) => {
+                        file.metadata.clone()
+                    }
+                    Some(_) => {
+                        return Err(CdnError::NotFound(format!(
+                            "Upload of {:?} is not complete",
+                            complete_path
+                        )))
+                    }
+                    None => None,
+                };
+                let metadata = metadata.ok_or_else(|| {
+                    CdnError::NotFound(format!("{:?} is not stored", complete_path))
+                })?;
+                self.touch(&hashed_path).await;
+                Value::Json(metadata.serialize()?)
+            }
+            Ok(chunk_number) => {
+                log::debug!("Getting chunk");
+                let path = extract_file_path(&resource_prefix, query_path)?;
+                let hashed_path = hash_path(&path);
+
+                // Chunks of an upload in progress are served to the servers
+                // retrieving the same generation.
+                let expected = properties.get(CHECKSUM_PROPERTY);
+                log::debug!(
+                    "Getting chunk {:?} for {:?} - stored in {:?}",
+                    chunk_number,
+                    path,
+                    hashed_path
+                );
+                match self
+                    .locate_chunk(&hashed_path, chunk_number, expected.map(|c| c.as_str()))
+                    .await
+                {
+                    ChunkLocation::Stored(chunk_path) => {
+                        let data = read_file_to_vec(&chunk_path).await?;
+                        self.touch(&hashed_path).await;
+                        data.into()
+                    }
+                    ChunkLocation::Missing => {
+                        return Err(CdnError::NotFound(format!(
+                            "Chunk {} of {:?} is not stored",
+                            chunk_number, path
+                        )))
+                    }
+                    ChunkLocation::Stale(current) => {
+                        log::debug!(
+                            "Chunk {:?} for {:?} is stale - expected {:?} current {:?}",
+                            chunk_number,
+                            path,
+                            expected,
+                            current
+                        );
+                        let (current, generation) = current.unzip();
+                        let stale = StaleChunk {
+                            resource_name: path.to_string(),
+                            expected: expected.cloned().unwrap_or_default(),
+                            current,
+                            generation,
+                        };
+                        Value::Json(stale.serialize()?)
+                    }
+                }
+            }
+        };
+        Ok(resp)
+    }
+
+    /// Fetches the metadata or chunk at `query_path` from the other servers,
+    /// stores it locally and returns it.
+    async fn pull_through(&self, query_path: &str, properties: &Properties) -> CdnResult<Value> {
+        let resource_prefix = self.resource_prefix();
+        let complete_path = extract_complete_file_path(&resource_prefix, query_path)?;
+
+        let chunk_number = match extract_chunk_number(&complete_path) {
+            Ok(chunk_number) => chunk_number,
+            Err(_) => {
+                let metadata = self.pull_metadata(query_path).await?;
+                return Ok(Value::Json(metadata.serialize()?));
+            }
+        };
+
+        let path = extract_file_path(&resource_prefix, query_path)?;
+        let hashed_path = hash_path(&path);
+        let metadata_path = format!("{}{}", resource_prefix, path);
+
+        // The chunk is only cached together with the metadata of its
+        // generation, refreshed if the client expects a different one.
+        let expected = properties.get(CHECKSUM_PROPERTY);
+        let current = match (self.stored_metadata(&hashed_path).await, expected) {
+            (Some(current), None) => current,
+            (Some(current), Some(expected)) if *expected == current.checksum => current,
+            _ => self.pull_metadata(&metadata_path).await?,
+        };
+        let checksum = current.checksum;
+        if let Some(expected) = expected {
+            if *expected != checksum {
+                let stale = StaleChunk {
+                    resource_name: path.to_string(),
+                    expected: expected.clone(),
+                    current: Some(checksum),
+                    generation: Some(current.generation),
+                };
+                return Ok(Value::Json(stale.serialize()?));
+            }
+        }
+
+        match self
+            .fetch_remote(query_path, &[(CHECKSUM_PROPERTY, &checksum)])
+            .await?
+        {
+            Value::Raw(_, buf) => {
+                let data = buf.to_vec();
+                self.validate_chunk(&path, chunk_number, &data).await?;
+                self.store_chunk(&path, None, chunk_number, &data).await?;
+                Ok(data.into())
+            }
+            // Stale chunk reported by the origin, the client will restart.
+            Value::Json(value) => Ok(Value::Json(value)),
+            _ => Err(CdnError::MalformedMetadata(format!(
+                "Chunk {:?} is not correctly formatted",
+                query_path
+            ))),
+        }
+    }
+
+    async fn pull_metadata(&self, metadata_path: &str) -> CdnResult<FileMetadata> {
+        match self.fetch_remote(metadata_path, &[]).await? {
+            Value::Json(value) => {
+                let metadata = FileMetadata::deserialize(&value)?;
+                if metadata.expired(SystemTime::now()) {
+                    return Err(CdnError::NotFound(format!(
+                        "File {:?} expired",
+                        metadata.resource_name
+                    )));
+                }
+                self.store_metadata(&value).await
+            }
+            _ => Err(CdnError::MalformedMetadata(format!(
+                "Metadata {:?} is not correctly formatted",
+                metadata_path
+            ))),
+        }
+    }
+
+    /// Queries the other servers for `path`, returning the first reply.
+    async fn fetch_remote(&self, path: &str, properties: &[(&str, &str)]) -> CdnResult<Value> {
+        let mut properties = properties.to_vec();
+        properties.push((FETCH_PROPERTY, "true"));
+        let selector = signed_selector(self.identity.as_ref(), path, &properties)?;
+
+        let ws = self.z.workspace(None).await?;
+        let mut ds = ws.get(&selector).await?;
+        match ds.next().await {
+            Some(data) => Ok(data.value),
+            None => Err(CdnError::NotFound(format!(
+                "No server replied for {:?}",
+                path
+            ))),
+        }
+    }
+
+    /// Returns the checksum of the generation served for `hashed_path`, or
+    /// `None` if no upload of the file completed.
+    async fn current_checksum(&self, hashed_path: &str) -> Option<String> {
+        self.index
+            .lock()
+            .await
+            .get(hashed_path)
+            .and_then(|file| file.metadata.as_ref().map(|m| m.checksum.clone()))
+    }
+
+    async fn process_sample(&self, sample: Option<Change>) -> CdnResult<()> {
+        let resource_prefix = self.resource_prefix();
+
+        let sample = match sample {
+            Some(s) => Ok(s),
+            None => Err(CdnError::Other("Subscriber received nothing".to_string())),
+        }?;
+        log::debug!("Received data from {:?}", sample.path);
+        if sample
+            .path
+            .as_str()
+            .starts_with(&SERVER_INFO_PATH!(self.root(), ""))
+        {
+            return self.process_heartbeat(sample).await;
+        }
+        if sample
+            .path
+            .as_str()
+            .starts_with(&FILE_COMMIT_PATH!(self.root(), SEPARATOR))
+        {
+            return self.process_commit(sample).await;
+        }
+        match sample.kind {
+            ChangeKind::Put | ChangeKind::Patch => {
+                let value = sample
+                    .value
+                    .ok_or_else(|| CdnError::Other("Sample is missing value".to_string()))?;
+
+                match value {
+                    Value::Raw(_, buf) => {
+                        let data = buf.to_vec();
+                        log::debug!("Received {:?} bytes", data.len());
+
+                        let path = extract_file_path(&resource_prefix, sample.path.as_str())?;
+                        let chunk_number = extract_chunk_number(sample.path.as_str())?;
+
+                        // Chunks are not signed, they are checked against the
+                        // digests of the metadata accepted under the rules.
+                        let accepts_uploads = match self.acl_rule(&path) {
+                            Some(rule) => !rule.upload.is_empty(),
+                            None => self.config.acl.is_empty(),
+                        };
+                        if !accepts_uploads {
+                            return Err(CdnError::Denied(format!(
+                                "Upload of chunk {} of {:?}",
+                                chunk_number, path
+                            )));
+                        }
+
+                        if !self.owns_chunk(&path, chunk_number).await {
+                            log::trace!(
+                                "Chunk {:?} of {:?} is stored by other servers",
+                                chunk_number,
+                                path
+                            );
+                            return Ok(());
+                        }
+
+                        self.check_chunk_size(&path, chunk_number, &data)?;
+                        self.ingest_chunk(&path, chunk_number, &data).await
+                    }
+                    Value::Json(value) => self.begin_upload(&value).await,
+                    _ => {
+                        log::error!("Subscriber received data not correctly formatted");
+                        Ok(())
+                    }
+                }
+            }
+            ChangeKind::Delete => {
+                let file_path = extract_complete_file_path(&resource_prefix, sample.path.as_str())?;
+                self.check_delete(&file_path)?;
+                let hashed_path = hash_path(&file_path);
+                let stored = self.index.lock().await.contains_key(&hashed_path);
+                if stored || path_exists(&self.file_dir(&hashed_path)).await {
+                    log::debug!("Removing {:?}", file_path);
+                    return self.remove_stored_file(&hashed_path).await;
+                }
+
+                let path = extract_file_path(&resource_prefix, sample.path.as_str())?;
+                let chunk_number = extract_chunk_number(sample.path.as_str())?;
+                self.remove_chunk(&path, chunk_number).await
+            }
+        }
+    }
+
+    /// Stores a chunk of the generation `checksum` being uploaded, or of the
+    /// generation served when `None`.
+    async fn store_chunk(
+        &self,
+        path: &str,
+        checksum: Option<&str>,
+        chunk_number: usize,
+        data: &[u8],
+    ) -> CdnResult<()> {
+        let hashed_path = hash_path(path);
+        let dir = match checksum {
+            Some(checksum) => self.staging_dir(&hashed_path, checksum),
+            None => self.file_dir(&hashed_path),
+        };
+        let chunk_path = dir.join(format!("{}", chunk_number));
+
+        log::debug!(
+            "Received {:?} Chunk {:?} - Hashed {:?} - Going to be stored in {:?}",
+            path,
+            chunk_number,
+            hashed_path,
+            chunk_path
+        );
+
+        self.reserve(&hashed_path, checksum, data.len() as u64)
+            .await?;
+        create_dir_if_not_exists(&dir).await?;
+        write_chunk_file(&chunk_path, data).await?;
+
+        let len = data.len() as u64;
+        self.update_index(&hashed_path, |file| match checksum {
+            Some(checksum) => file.set_staged_chunk(checksum, chunk_number, len),
+            None => file.set_chunk(chunk_number, len),
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Stores a chunk published by a client with the upload in progress
+    /// whose digest it matches, the most recent first, or with the
+    /// generation served when it restores one of its chunks.
+    async fn ingest_chunk(&self, path: &str, chunk_number: usize, data: &[u8]) -> CdnResult<()> {
+        let hashed_path = hash_path(path);
+        let (mut uploads, served) = match self.index.lock().await.get(&hashed_path) {
+            Some(file) => (
+                file.staged
+                    .values()
+                    .map(|upload| {
+                        let order = (upload.metadata.generation, upload.started);
+                        (order, upload.metadata.clone())
+                    })
+                    .collect::<Vec<_>>(),
+                file.metadata.clone(),
+            ),
+            None => (Vec::new(), None),
+        };
+        uploads.sort_by_key(|(order, _)| std::cmp::Reverse(*order));
+
+        let mut error = None;
+        for (_, metadata) in uploads {
+            match metadata.validate_chunk(chunk_number, data) {
+                Ok(()) => {
+                    let checksum = Some(metadata.checksum.as_str());
+                    self.store_chunk(path, checksum, chunk_number, data).await?;
+                    return self.try_complete(path, &metadata.checksum).await;
+                }
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        if let Some(metadata) = served {
+            if metadata.validate_chunk(chunk_number, data).is_ok() {
+                return self.store_chunk(path, None, chunk_number, data).await;
+            }
+        }
+        Err(error.unwrap_or_else(|| {
+            CdnError::Integrity(format!(
+                "Chunk {} of {:?} matches no upload in progress",
+                chunk_number, path
+            ))
+        }))
+    }
+
+    fn check_chunk_size(&self, path: &str, chunk_number: usize, data: &[u8]) -> CdnResult<()> {
+        if data.len() > self.config.max_chunk_size {
+            return Err(CdnError::Integrity(format!(
+                "Chunk {} of {:?} has {} bytes, more than the maximum of {}",
+                chunk_number,
+                path,
+                data.len(),
+                self.config.max_chunk_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks a chunk against the maximum chunk size and the generation
+    /// served for its file, if any.
+    async fn validate_chunk(&self, path: &str, chunk_number: usize, data: &[u8]) -> CdnResult<()> {
+        self.check_chunk_size(path, chunk_number, data)?;
+        match self.stored_metadata(&hash_path(path)).await {
+            Some(metadata) => metadata.validate_chunk(chunk_number, data),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes a chunk of the generation served, it is missing until stored
+    /// again.
+    async fn remove_chunk(&self, path: &str, chunk_number: usize) -> CdnResult<()> {
+        let hashed_path = hash_path(path);
+        let complete_path = self.config.chunks_dir.join(&hashed_path);
+        let chunk_path = complete_path.join(format!("{}", chunk_number));
+        if !path_exists(&chunk_path).await {
+            return Ok(());
+        }
+
+        log::debug!("Removing chunk {:?} of {:?}", chunk_number, path);
+        remove_file_if_exists(&chunk_path).await?;
+        self.update_index(&hashed_path, |file| file.remove_chunk(chunk_number))
+            .await;
+        Ok(())
+    }
+
+    /// Handles the commit of an upload, the file is complete once all the
+    /// chunks this server owns are stored and verified.
+    async fn process_commit(&self, sample: Change) -> CdnResult<()> {
+        let value = match (sample.kind, sample.value) {
+            (ChangeKind::Put, Some(Value::Json(value))) => value,
+            _ => return Ok(()),
+        };
+        let commit = UploadCommit::deserialize(&value)?;
+        let commit_prefix = FILE_COMMIT_PATH!(self.root(), "");
+        let path = extract_complete_file_path(&commit_prefix, sample.path.as_str())?;
+        if commit.resource_name != path.as_str() {
+            return Err(CdnError::MalformedMetadata(format!(
+                "Commit of {:?} published for {:?}",
+                commit.resource_name, path
+            )));
+        }
+
+        check_checksum(&commit.checksum)?;
+
+        if commit.abort {
+            log::debug!("Received abort of the upload of {:?}", path);
+            return self
+                .remove_upload(&hash_path(&path), &commit.checksum)
+                .await;
+        }
+        log::debug!("Received commit of {:?}", path);
+        self.commit_upload(&path, &commit.checksum).await
+    }
+
+    /// Marks the upload of the generation `checksum` as committed, it is
+    /// served once complete.
+    async fn commit_upload(&self, path: &str, checksum: &str) -> CdnResult<()> {
+        let hashed_path = hash_path(path);
+        let (staged, served) = match self.index.lock().await.get(&hashed_path) {
+            Some(file) => (
+                file.staged.contains_key(checksum),
+                file.metadata.as_ref().map(|m| m.checksum == checksum),
+            ),
+            None => (false, None),
+        };
+        match (staged, served) {
+            (true, _) => (),
+            // Commit published again after the upload completed.
+            (false, Some(true)) => return Ok(()),
+            (false, _) => {
+                return Err(CdnError::NotFound(format!(
+                    "No upload of {:?} with checksum {:?} to commit",
+                    path, checksum
+                )))
+            }
+        }
+
+        let dir = self.staging_dir(&hashed_path, checksum);
+        write_chunk_file(&dir.join(COMMITTED_FILE), &[]).await?;
+        self.update_index(&hashed_path, |file| {
+            if let Some(upload) = file.staged.get_mut(checksum) {
+                upload.committed = true;
+                upload.touched = SystemTime::now();
+            }
+        })
+        .await;
+        self.try_complete(path, checksum).await
+    }
+
+    /// Serves the generation `checksum` of a file once its upload is
+    /// committed and all the chunks this server owns are stored. Chunks are
+    /// verified when stored, only the index is checked.
+    async fn try_complete(&self, path: &str, checksum: &str) -> CdnResult<()> {
+        let hashed_path = hash_path(path);
+        let missing = match self
+            .index
+            .lock()
+            .await
+            .get(&hashed_path)
+            .and_then(|file| file.staged.get(checksum))
+        {
+            Some(upload) if upload.committed => (0..upload.metadata.chunks)
+                .filter(|i| !upload.chunks.contains_key(i))
+                .collect::<Vec<usize>>(),
+            _ => return Ok(()),
+        };
+        for i in missing {
+            if self.owns_chunk(path, i).await {
+                log::trace!("Upload of {:?} is waiting for chunk {}", path, i);
+                return Ok(());
+            }
+        }
+
+        log::debug!("Upload of {:?} is complete", path);
+        let file_dir = self.file_dir(&hashed_path);
+        let staging_dir = self.staging_dir(&hashed_path, checksum);
+        remove_dir_if_exists(&file_dir).await?;
+        rename_file(&staging_dir, &file_dir).await?;
+        remove_file_if_exists(&file_dir.join(COMMITTED_FILE)).await?;
+        if let Some(parent) = staging_dir.parent() {
+            // Only removed once no other upload of the file is staged.
+            let _ = async_std::fs::remove_dir(parent).await;
+        }
+        self.update_index(&hashed_path, |file| file.promote(checksum))
+            .await;
+        Ok(())
+    }
+
+    /// Drops the upload of the generation `checksum`, the generation served
+    /// is kept.
+    async fn remove_upload(&self, hashed_path: &str, checksum: &str) -> CdnResult<()> {
+        let staging_dir = self.staging_dir(hashed_path, checksum);
+        remove_dir_if_exists(&staging_dir).await?;
+        if let Some(parent) = staging_dir.parent() {
+            let _ = async_std::fs::remove_dir(parent).await;
+        }
+        let mut index = self.index.lock().await;
+        if let Some(file) = index.get_mut(hashed_path) {
+            file.remove_staged(checksum);
+            if file.is_empty() {
+                index.remove(hashed_path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks metadata received against the trusted publishers, the access
+    /// control rules and the quota.
+    fn accept_metadata(&self, value: &str) -> CdnResult<FileMetadata> {
+        let metadata = FileMetadata::deserialize(value)?;
+        check_checksum(&metadata.checksum)?;
+        metadata.check_layout()?;
+        if metadata.chunk_size > self.config.max_chunk_size {
+            return Err(CdnError::MalformedMetadata(format!(
+                "Chunks of {} bytes of {:?} exceed {} bytes",
+                metadata.chunk_size, metadata.resource_name, self.config.max_chunk_size
+            )));
+        }
+        // The unsigned chunk puts are authenticated by the digests.
+        if !self.config.acl.is_empty() && metadata.digests.len() != metadata.chunks {
+            return Err(CdnError::Denied(format!(
+                "Upload of {:?} without the digests of its chunks",
+                metadata.resource_name
+            )));
+        }
+        verify_metadata(&metadata, &self.config.trusted_publishers)?;
+        let publisher = metadata_publisher(&metadata)?;
+        self.check_access(
+            Access::Upload,
+            &metadata.resource_name,
+            publisher.as_deref(),
+        )?;
+        if let Some(quota) = self.config.quota {
+            if metadata.size > quota {
+                return Err(CdnError::QuotaExceeded(format!(
+                    "Refusing {:?} of {} bytes, larger than the quota of {} bytes",
+                    metadata.resource_name, metadata.size, quota
+                )));
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Stages the generation announced by the metadata a client publishes
+    /// before the chunks of an upload. The generation served, if any, is
+    /// replaced once the upload is committed and complete.
+    async fn begin_upload(&self, value: &str) -> CdnResult<()> {
+        let metadata = self.accept_metadata(value)?;
+        let hashed_path = hash_path(&metadata.resource_name);
+        let dir = self.staging_dir(&hashed_path, &metadata.checksum);
+        let metadata_path = dir.join("metadata");
+
+        log::debug!(
+            "Received Metadata {:?} - Going to be staged in {:?}",
+            metadata,
+            metadata_path
+        );
+
+        let replaced = self
+            .update_index(&hashed_path, |file| {
+                file.stage(metadata, value.len() as u64)
+            })
+            .await;
+        if replaced {
+            log::debug!("Replacing the upload staged in {:?}", dir);
+            remove_dir_if_exists(&dir).await?;
+        }
+        create_dir_if_not_exists(&dir).await?;
+        write_metadata_file(&metadata_path, value).await?;
+        Ok(())
+    }
+
+    /// Stores the metadata retrieved from other servers as the generation
+    /// served. The chunks of the generation it replaces are removed, whereas
+    /// an older generation is ignored and the one served returned instead.
+    async fn store_metadata(&self, value: &str) -> CdnResult<FileMetadata> {
+        let metadata = self.accept_metadata(value)?;
+        let hashed_path = hash_path(&metadata.resource_name);
+        let complete_path = self.file_dir(&hashed_path);
+
+        match self.stored_metadata(&hashed_path).await {
+            Some(served) if !metadata.supersedes(&served) => {
+                if served.checksum != metadata.checksum {
+                    log::debug!(
+                        "Keeping the generation {:?} of {:?}, newer than {:?}",
+                        served.checksum,
+                        served.resource_name,
+                        metadata.checksum
+                    );
+                }
+                return Ok(served);
+            }
+            Some(served) => {
+                log::debug!(
+                    "Removing the generation {:?} of {:?}, replaced by {:?}",
+                    served.checksum,
+                    served.resource_name,
+                    metadata.checksum
+                );
+                remove_dir_if_exists(&complete_path).await?;
+                self.update_index(&hashed_path, |file| file.clear_served())
+                    .await;
+            }
+            None => (),
+        }
+        let metadata_path = complete_path.join("metadata");
+
+        log::debug!(
+            "Received Metadata {:?} - Going to be stored in {:?}",
+            metadata,
+            metadata_path
+        );
+
+        // The chunks are only fetched on demand, the file is not complete.
+        self.update_index(&hashed_path, |file| {
+            file.set_metadata(metadata.clone(), value.len() as u64);
+            file.cached = true;
+        })
+        .await;
+        create_dir_if_not_exists(&complete_path).await?;
+        write_chunk_file(&complete_path.join(CACHED_FILE), &[]).await?;
+        write_metadata_file(&metadata_path, value).await?;
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(checksum: &str, generation: u64) -> FileMetadata {
+        FileMetadata::deserialize(&format!(
+            r#"{{"filename":"b","checksum":"{}","chunk_size":1024,"chunks":2,
+                "resource_name":"/a/b","size":1500,"generation":{}}}"#,
+            checksum, generation
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn stored_file_accounts_for_every_byte() {
+        let mut file = StoredFile::new();
+        file.set_metadata(metadata("AA", 1), 100);
+        file.set_chunk(0, 1024);
+        file.set_chunk(1, 476);
+        file.set_chunk(1, 476);
+        assert_eq!(file.size, 1600);
+
+        file.stage(metadata("BB", 2), 100);
+        file.set_staged_chunk("BB", 0, 1024);
+        assert_eq!(file.size, 2724);
+        file.remove_chunk(1);
+        assert_eq!(file.size, 2248);
+
+        file.promote("BB");
+        assert_eq!(file.size, 1124);
+        assert_eq!(file.metadata.as_ref().unwrap().checksum, "BB");
+        file.clear_served();
+        assert_eq!(file.size, 0);
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn cached_files_are_not_complete() {
+        let mut file = StoredFile::new();
+        file.set_metadata(metadata("AA", 1), 100);
+        assert!(file.complete());
+        file.cached = true;
+        assert!(!file.complete());
+
+        file.stage(metadata("BB", 2), 100);
+        file.promote("BB");
+        assert!(file.complete());
+    }
+
+    #[test]
+    fn restaging_other_metadata_drops_the_upload() {
+        let mut file = StoredFile::new();
+        assert!(!file.stage(metadata("BB", 2), 100));
+        file.set_staged_chunk("BB", 0, 1024);
+        assert!(!file.stage(metadata("BB", 2), 100));
+        assert_eq!(file.size, 1124);
+
+        assert!(file.stage(metadata("BB", 3), 120));
+        assert_eq!(file.size, 120);
+        assert!(file.staged["BB"].chunks.is_empty());
+        assert_eq!(file.staged["BB"].metadata.generation, 3);
+        file.remove_staged("BB");
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn only_superseding_uploads_discount_the_generation_served() {
+        let mut file = StoredFile::new();
+        file.set_metadata(metadata("BB", 2), 100);
+        file.set_chunk(0, 1024);
+        file.stage(metadata("CC", 3), 100);
+        file.stage(metadata("AA", 1), 100);
+
+        assert_eq!(file.superseded_size(Some("CC")), 1124);
+        assert_eq!(file.superseded_size(Some("AA")), 0);
+        assert_eq!(file.superseded_size(Some("DD")), 0);
+        assert_eq!(file.superseded_size(None), 0);
+    }
 }
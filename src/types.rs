@@ -12,16 +12,55 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 
+use crate::error::{CdnError, CdnResult};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use zenoh::{ZError, ZErrorKind, ZResult};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub static FILES_KEY: &str = "files";
 pub static METADATA_KEY: &str = "metadata";
+pub static SERVERS_KEY: &str = "@servers";
+pub static ADMIN_KEY: &str = "@admin";
+pub static SERVER_ID_FILE: &str = "server_id";
 pub static DEFAULT_ROOT: &str = "/zenohcdn";
 pub static SEPARATOR: &str = "/";
+pub static PENDING_FILE: &str = "pending";
+pub static COMMITTED_FILE: &str = "committed";
+pub static CACHED_FILE: &str = "cached";
+pub static COMMITS_KEY: &str = "commits";
+pub static QUARANTINE_DIR: &str = ".quarantine";
+pub static STAGING_DIR: &str = ".staging";
+pub static CHECKSUM_PROPERTY: &str = "checksum";
+pub static FETCH_PROPERTY: &str = "fetch";
+pub static SERVER_PROPERTY: &str = "server";
+pub static IDENTITY_PROPERTY: &str = "identity";
+pub static SIGNATURE_PROPERTY: &str = "signature";
+pub static TIMESTAMP_PROPERTY: &str = "timestamp";
+pub static NONCE_PROPERTY: &str = "nonce";
 
 pub static DEFAULT_CHUNK_SIZE: usize = 1_048_576; //1MB
+pub static DEFAULT_DOWNLOAD_ATTEMPTS: usize = 3;
+pub static DEFAULT_PARALLEL_CHUNKS: usize = 8;
+pub static DEFAULT_REQUEST_TIMEOUT: u64 = 10_000; //milliseconds
+pub static DEFAULT_MAX_RETRIES: usize = 3;
+pub static DEFAULT_BACKOFF: u64 = 100; //milliseconds
+pub static DEFAULT_MAX_BACKOFF: u64 = 10_000; //milliseconds
+pub static DEFAULT_SWEEP_INTERVAL: u64 = 60; //seconds
+pub static DEFAULT_HEARTBEAT_INTERVAL: u64 = 10; //seconds
+pub static MAX_HEARTBEAT_INTERVAL: u64 = 3_600; //seconds
+pub static DEFAULT_MAX_CHUNK_SIZE: usize = 2 * 1_048_576; //2MB, room for codec overhead
+pub static DEFAULT_GC_GRACE_PERIOD: u64 = 86_400; //seconds
+pub static DEFAULT_GC_INTERVAL: u64 = 3_600; //seconds
+pub static DEFAULT_QUARANTINE_MAX_AGE: u64 = 604_800; //seconds
+pub static DEFAULT_SCRUB_RATE: u64 = 1_048_576; //bytes per second
+pub static DEFAULT_SCRUB_INTERVAL: u64 = 86_400; //seconds
+pub static REQUEST_SIGNATURE_VALIDITY: u64 = 300; //seconds
+pub static MAX_RESOURCE_NAME_LEN: usize = 1024;
+pub static MAX_FILENAME_LEN: usize = 255;
+pub static MAX_RECENT_ERRORS: usize = 100;
+pub static ENCRYPTION_TAG_LEN: usize = 16; //Poly1305 tag
 
 #[macro_export]
 macro_rules! LIST_FILE_PATH {
@@ -36,6 +75,27 @@ macro_rules! LIST_FILE_PATH {
     };
 }
 
+#[macro_export]
+macro_rules! LIST_SERVERS_PATH {
+    ($prefix:expr) => {
+        format!("{}/{}/{}", $prefix, $crate::types::SERVERS_KEY, "*")
+    };
+}
+
+#[macro_export]
+macro_rules! SERVER_INFO_PATH {
+    ($prefix:expr, $id:expr) => {
+        format!("{}/{}/{}", $prefix, $crate::types::SERVERS_KEY, $id)
+    };
+}
+
+#[macro_export]
+macro_rules! ADMIN_PATH {
+    ($prefix:expr, $id:expr) => {
+        format!("{}/{}/{}", $prefix, $crate::types::ADMIN_KEY, $id)
+    };
+}
+
 #[macro_export]
 macro_rules! GET_FILE_METADATA_PATH {
     ($prefix:expr, $hash:expr) => {
@@ -71,12 +131,12 @@ macro_rules! GET_FILE_PATH {
 
 #[macro_export]
 macro_rules! FILE_CHUNK_PATH {
-    ($prefix:expr, $hash:expr, $chunk:expr) => {
+    ($prefix:expr, $resource:expr, $chunk:expr) => {
         format!(
-            "{}/{}/{}/{}",
+            "{}/{}{}/{}",
             $prefix,
             $crate::types::FILES_KEY,
-            $hash,
+            $resource,
             $chunk
         )
     };
@@ -84,38 +144,416 @@ macro_rules! FILE_CHUNK_PATH {
 
 #[macro_export]
 macro_rules! FILE_METADATA_PATH {
-    ($prefix:expr, $hash:expr) => {
-        format!("{}/{}/{}", $prefix, $crate::types::FILES_KEY, $hash)
+    ($prefix:expr, $resource:expr) => {
+        format!("{}/{}{}", $prefix, $crate::types::FILES_KEY, $resource)
     };
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[macro_export]
+macro_rules! FILE_COMMIT_PATH {
+    ($prefix:expr, $resource:expr) => {
+        format!("{}/{}{}", $prefix, $crate::types::COMMITS_KEY, $resource)
+    };
+}
+
+/// Name of a file in Zenoh-CDN, an absolute path such as `/maps/site-a.bin`.
+///
+/// Segments cannot be empty, `.` or `..`, and the name cannot contain control
+/// characters, zenoh wildcards or more than `MAX_RESOURCE_NAME_LEN` bytes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ResourceName(String);
+
+impl ResourceName {
+    pub fn new(name: &str) -> CdnResult<Self> {
+        let invalid = |reason: &str| {
+            Err(CdnError::InvalidName(format!(
+                "Resource name {:?} {}",
+                name, reason
+            )))
+        };
+        if name.len() > MAX_RESOURCE_NAME_LEN {
+            return invalid("is too long");
+        }
+        let relative = match name.strip_prefix('/') {
+            Some(relative) => relative,
+            None => return invalid("does not start with '/'"),
+        };
+        if let Some(c) = name
+            .chars()
+            .find(|c| c.is_control() || matches!(c, '*' | '?' | '#' | '$'))
+        {
+            return invalid(&format!("contains {:?}", c));
+        }
+        for segment in relative.split('/') {
+            match segment {
+                "" => return invalid("has an empty segment"),
+                "." | ".." => return invalid(&format!("has a {:?} segment", segment)),
+                _ => (),
+            }
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ResourceName {
+    type Err = CdnError;
+
+    fn from_str(s: &str) -> CdnResult<Self> {
+        Self::new(s)
+    }
+}
+
+impl std::ops::Deref for ResourceName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// Checks the name under which a downloaded file may be saved: a single
+/// path segment without control characters.
+pub fn validate_filename(filename: &str) -> CdnResult<()> {
+    let valid = !filename.is_empty()
+        && filename.len() <= MAX_FILENAME_LEN
+        && filename != "."
+        && filename != ".."
+        && !filename
+            .chars()
+            .any(|c| c.is_control() || c == '/' || c == '\\');
+    match valid {
+        true => Ok(()),
+        false => Err(CdnError::InvalidName(format!(
+            "Filename {:?} is not valid",
+            filename
+        ))),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FileMetadata {
     pub filename: String,
+    /// MD5 of the original file. For encrypted files it is the
+    /// `digests_checksum`, which discloses nothing about the content.
     pub checksum: String,
     pub chunk_size: usize,
     pub chunks: usize,
     pub resource_name: String,
     pub size: u64,
+    /// Time-to-live in seconds, counted from the `generation` so that all the
+    /// servers expire the file together.
+    pub ttl: Option<u64>,
+    /// Milliseconds since the epoch when the upload started, orders the
+    /// generations of the file. Zero for files uploaded by older clients.
+    #[serde(default)]
+    pub generation: u64,
+    /// Codec applied to every chunk, `size` and `checksum` are those of the
+    /// original file.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Set when the chunks are encrypted by the client, applied after the
+    /// compression.
+    pub encryption: Option<Encryption>,
+    /// SHA-256 of every chunk as stored by the servers.
+    #[serde(default)]
+    pub digests: Vec<String>,
+    /// Signature of the publisher over all the other fields.
+    pub signature: Option<MetadataSignature>,
+}
+
+/// Ed25519 signature of a `FileMetadata` or a `ServerInfo`, serialized
+/// without its signature.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MetadataSignature {
+    /// Hex encoded public key of the publisher.
+    pub public_key: String,
+    /// Hex encoded signature.
+    pub signature: String,
+}
+
+/// How the chunks of a file are encrypted, the key itself never leaves the
+/// clients.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Encryption {
+    /// Identifier of the key supplied by the uploader.
+    pub key_id: String,
+    /// Hex encoded random prefix of the nonces, completed by the chunk number.
+    pub nonce: String,
+}
+
+/// Compression codec applied by the client to each chunk before upload, the
+/// servers store the compressed chunks as they are.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = CdnError;
+
+    fn from_str(s: &str) -> CdnResult<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(CdnError::Other(format!("Unknown compression {:?}", s))),
+        }
+    }
+}
+
+impl Compression {
+    pub fn compress(&self, data: &[u8]) -> CdnResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::stream::encode_all(data, 0)
+                .map_err(|e| CdnError::Other(format!("Unable to compress chunk {:?}", e))),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    /// Largest size of a chunk of `len` bytes once compressed.
+    pub fn max_compressed_len(&self, len: usize) -> usize {
+        match self {
+            Compression::None => len,
+            Compression::Zstd => zstd::zstd_safe::compress_bound(len),
+            Compression::Lz4 => 4 + lz4_flex::block::get_maximum_output_size(len),
+        }
+    }
+
+    /// Reverses `compress`, `capacity` is the maximum size of the original
+    /// chunk. Nothing larger is ever allocated, whatever the chunk claims.
+    pub fn decompress(&self, data: &[u8], capacity: usize) -> CdnResult<Vec<u8>> {
+        match self {
+            Compression::None => match data.len() > capacity {
+                true => Err(CdnError::Integrity(format!(
+                    "Chunk of {} bytes exceeds {} bytes",
+                    data.len(),
+                    capacity
+                ))),
+                false => Ok(data.to_vec()),
+            },
+            Compression::Zstd => zstd::block::decompress(data, capacity)
+                .map_err(|e| CdnError::Integrity(format!("Unable to decompress chunk {:?}", e))),
+            Compression::Lz4 => {
+                if data.len() < 4 {
+                    return Err(CdnError::Integrity(
+                        "Compressed chunk is missing its size".to_string(),
+                    ));
+                }
+                let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if size > capacity {
+                    return Err(CdnError::Integrity(format!(
+                        "Decompressed chunk of {} bytes exceeds {} bytes",
+                        size, capacity
+                    )));
+                }
+                lz4_flex::decompress(&data[4..], size)
+                    .map_err(|e| CdnError::Integrity(format!("Unable to decompress chunk {:?}", e)))
+            }
+        }
+    }
 }
 
 impl FileMetadata {
-    pub fn serialize(&self) -> ZResult<String> {
+    pub fn serialize(&self) -> CdnResult<String> {
         serde_json::to_string(self).map_err(|e| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: format!("Error serializing metadata {:?} information {}", self, e)
-            })
+            CdnError::Other(format!(
+                "Error serializing metadata {:?} information {}",
+                self, e
+            ))
+        })
+    }
+
+    pub fn deserialize(serialized: &str) -> CdnResult<Self> {
+        let metadata: Self = serde_json::from_str(serialized).map_err(|e| {
+            CdnError::MalformedMetadata(format!(
+                "Error deserializing metadata {:?} information {}",
+                serialized, e
+            ))
+        })?;
+        ResourceName::new(&metadata.resource_name)?;
+        validate_filename(&metadata.filename)?;
+        Ok(metadata)
+    }
+
+    /// SHA-256 over the digests of all the chunks as stored.
+    pub fn digests_checksum(&self) -> String {
+        hash_chunk(self.digests.concat().as_bytes())
+    }
+
+    /// Seconds elapsed at `now` since the upload of this generation started,
+    /// `None` for files uploaded by older clients.
+    pub fn age(&self, now: SystemTime) -> Option<u64> {
+        match self.generation {
+            0 => None,
+            generation => Some(
+                now.duration_since(UNIX_EPOCH + Duration::from_millis(generation))
+                    .unwrap_or_default()
+                    .as_secs(),
+            ),
+        }
+    }
+
+    /// Whether the TTL of this generation expired at `now`.
+    pub fn expired(&self, now: SystemTime) -> bool {
+        matches!((self.ttl, self.age(now)), (Some(ttl), Some(age)) if age >= ttl)
+    }
+
+    /// Whether this generation of the file replaces `other`, the checksum
+    /// breaks ties so that all the servers keep the same one.
+    pub fn supersedes(&self, other: &FileMetadata) -> bool {
+        (self.generation, &self.checksum) > (other.generation, &other.checksum)
+    }
+
+    /// Checks that the number of chunks, the chunk size, the size and the
+    /// digests of the file agree. Every chunk but the last one is full.
+    pub fn check_layout(&self) -> CdnResult<()> {
+        let chunk_size = self.chunk_size as u64;
+        let chunks = self.chunks as u64;
+        let consistent = chunk_size > 0
+            && chunks > 0
+            && matches!((chunks - 1).checked_mul(chunk_size),
+                Some(full) if full <= self.size && self.size - full <= chunk_size)
+            && (self.digests.is_empty() || self.digests.len() == self.chunks);
+        match consistent {
+            true => Ok(()),
+            false => Err(CdnError::MalformedMetadata(format!(
+                "Inconsistent layout of {:?}: {} bytes in {} chunks of {} bytes, {} digests",
+                self.resource_name,
+                self.size,
+                self.chunks,
+                self.chunk_size,
+                self.digests.len()
+            ))),
+        }
+    }
+
+    /// Length of the chunk `chunk` once decoded.
+    pub fn chunk_len(&self, chunk: usize) -> usize {
+        let offset = (chunk as u64).saturating_mul(self.chunk_size as u64);
+        self.size.saturating_sub(offset).min(self.chunk_size as u64) as usize
+    }
+
+    /// Checks that `data` can be the chunk `chunk` of this file: its index is
+    /// in range, its length fits the chunk once encoded and it matches its
+    /// digest when there are digests.
+    pub fn validate_chunk(&self, chunk: usize, data: &[u8]) -> CdnResult<()> {
+        if chunk >= self.chunks {
+            return Err(CdnError::Integrity(format!(
+                "Chunk {} of {:?} is out of range, the file has {} chunks",
+                chunk, self.resource_name, self.chunks
+            )));
+        }
+        let tag = match self.encryption {
+            Some(_) => ENCRYPTION_TAG_LEN,
+            None => 0,
+        };
+        let expected = self.compression.max_compressed_len(self.chunk_len(chunk)) + tag;
+        let valid = match self.compression {
+            Compression::None => data.len() == expected,
+            _ => data.len() <= expected,
+        };
+        if !valid {
+            return Err(CdnError::Integrity(format!(
+                "Chunk {} of {:?} expected {} bytes got {}",
+                chunk,
+                self.resource_name,
+                expected,
+                data.len()
+            )));
+        }
+        if let Some(digest) = self.digests.get(chunk) {
+            let actual = hash_chunk(data);
+            if *digest != actual {
+                return Err(CdnError::Integrity(format!(
+                    "Chunk {} of {:?} expected digest {:?} got {:?}",
+                    chunk, self.resource_name, digest, actual
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reply sent by a server instead of a chunk when the chunk it stores does not
+/// belong to the file generation (checksum) requested by the client.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StaleChunk {
+    pub resource_name: String,
+    pub expected: String,
+    pub current: Option<String>,
+    /// Generation of `current`, unknown from older servers.
+    #[serde(default)]
+    pub generation: Option<u64>,
+}
+
+impl StaleChunk {
+    pub fn serialize(&self) -> CdnResult<String> {
+        serde_json::to_string(self).map_err(|e| {
+            CdnError::Other(format!(
+                "Error serializing stale chunk {:?} information {}",
+                self, e
+            ))
         })
     }
 
-    pub fn deserialize(serialized: &str) -> ZResult<Self> {
+    pub fn deserialize(serialized: &str) -> CdnResult<Self> {
         serde_json::from_str(serialized).map_err(|e| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: format!(
-                    "Error deserializing metadata {:?} information {}",
-                    serialized, e
-                )
-            })
+            CdnError::MalformedMetadata(format!(
+                "Error deserializing stale chunk {:?} information {}",
+                serialized, e
+            ))
+        })
+    }
+}
+
+/// Published by the client at `FILE_COMMIT_PATH` once all the chunks of the
+/// generation `checksum` of a file have been sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadCommit {
+    pub resource_name: String,
+    pub checksum: String,
+    /// Drops the upload instead, the generation already served is kept.
+    #[serde(default)]
+    pub abort: bool,
+}
+
+impl UploadCommit {
+    pub fn serialize(&self) -> CdnResult<String> {
+        serde_json::to_string(self).map_err(|e| {
+            CdnError::Other(format!(
+                "Error serializing upload commit {:?} information {}",
+                self, e
+            ))
+        })
+    }
+
+    pub fn deserialize(serialized: &str) -> CdnResult<Self> {
+        serde_json::from_str(serialized).map_err(|e| {
+            CdnError::MalformedMetadata(format!(
+                "Error deserializing upload commit {:?} information {}",
+                serialized, e
+            ))
         })
     }
 }
@@ -124,44 +562,472 @@ impl FileMetadata {
 pub struct ServerConfig {
     pub chunks_dir: std::path::PathBuf,
     pub resource_space: String,
+    /// Identifier of the server, generated and persisted in `chunks_dir`
+    /// when not set.
+    pub server_id: Option<String>,
+    /// Seconds between two heartbeats announcing the server.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Removal of the abandoned partial uploads, disabled when not set.
+    pub gc: Option<GcConfig>,
+    /// Verification of the stored chunks, disabled when not set.
+    pub scrub: Option<ScrubConfig>,
+    /// Maximum number of bytes stored in `chunks_dir`, least recently used
+    /// files are evicted to make room for new uploads.
+    pub quota: Option<u64>,
+    /// Pull-through cache mode: on a miss the server fetches the metadata or
+    /// chunk from the other servers, stores it and replies.
+    #[serde(default)]
+    pub cache: bool,
+    /// Seconds between two synchronizations with the other servers, used to
+    /// retrieve the files uploaded while this server was offline.
+    pub anti_entropy_interval: Option<u64>,
+    pub replication: Option<ReplicationConfig>,
+    /// Bytes per second of chunk data sent in replies to queries.
+    pub reply_rate_limit: Option<u64>,
+    /// Largest chunk accepted, whatever the metadata of its file.
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: usize,
+    /// Hex encoded public keys of the publishers whose files are stored,
+    /// unsigned files are accepted when empty.
+    #[serde(default)]
+    pub trusted_publishers: Vec<String>,
+    /// Access control rules, everything is allowed when empty.
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+    /// Identities allowed to query the status under `ADMIN_PATH`, `*`
+    /// allowing anyone. The status is open to anyone only when both these and
+    /// the access control rules are empty.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// Hex encoded Ed25519 secret signing the queries this server sends to
+    /// the other servers, so that their access control rules apply, and its
+    /// heartbeats.
+    pub signing_key: Option<String>,
 }
 
-pub fn extract_file_path(prefix: &str, path: &str) -> ZResult<String> {
-    log::trace!("extract_file_path({:?},{:?}", prefix, path);
-    let p = path.strip_prefix(prefix).ok_or_else(|| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: "Unable to get resource name".to_string()
+/// Identities allowed to access the resources under `prefix`, the rule with
+/// the longest matching prefix applies and resources matching no rule are
+/// denied.
+///
+/// Identities are hex encoded Ed25519 public keys: the publisher signing the
+/// metadata for uploads, the key signing the query for reads. `*` allows
+/// anyone, including the unsigned requests.
+///
+/// Chunk puts are not signed, they are only accepted when they match a
+/// digest of metadata accepted under the `upload` rules. Deletes, commits and
+/// aborts cannot be signed either: deletes are accepted from anyone when
+/// `delete` is set and from no one otherwise, and an abort only drops an
+/// upload in progress, never the generation served.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AclRule {
+    pub prefix: String,
+    #[serde(default)]
+    pub upload: Vec<String>,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub delete: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Upload,
+    Read,
+}
+
+impl AclRule {
+    pub fn matches(&self, resource_name: &str) -> bool {
+        match resource_name.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.ends_with('/'),
+            None => false,
+        }
+    }
+
+    pub fn identities(&self, access: Access) -> &[String] {
+        match access {
+            Access::Upload => &self.upload,
+            Access::Read => &self.read,
+        }
+    }
+}
+
+/// The rule of `acl` applying to `resource_name`, the one with the longest
+/// matching prefix.
+pub fn acl_rule<'a>(acl: &'a [AclRule], resource_name: &str) -> Option<&'a AclRule> {
+    acl.iter()
+        .filter(|rule| rule.matches(resource_name))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Placement of the chunks across servers: each chunk is stored only by the
+/// `factor` members with the highest rendezvous score for it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplicationConfig {
+    pub factor: usize,
+    /// Server identifiers taking part in the placement, the servers
+    /// discovered through their heartbeats are used when empty.
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+/// Heartbeat periodically published by every server, also returned when
+/// querying `LIST_SERVERS_PATH`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerInfo {
+    pub id: String,
+    pub quota: Option<u64>,
+    pub used_bytes: u64,
+    pub heartbeat_interval: u64,
+    /// Seconds since the epoch when the heartbeat was signed.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Signature of the server over all the other fields.
+    pub signature: Option<MetadataSignature>,
+}
+
+impl ServerInfo {
+    pub fn serialize(&self) -> CdnResult<String> {
+        serde_json::to_string(self).map_err(|e| {
+            CdnError::Other(format!(
+                "Error serializing server {:?} information {}",
+                self, e
+            ))
+        })
+    }
+
+    pub fn deserialize(serialized: &str) -> CdnResult<Self> {
+        serde_json::from_str(serialized).map_err(|e| {
+            CdnError::MalformedMetadata(format!(
+                "Error deserializing server {:?} information {}",
+                serialized, e
+            ))
+        })
+    }
+}
+
+/// Status of a server, returned when querying under `ADMIN_PATH`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminStatus {
+    pub id: String,
+    /// Seconds since the server started.
+    pub uptime: u64,
+    pub file_count: usize,
+    pub complete_file_count: usize,
+    /// Bytes stored in `chunks_dir`, the quarantined chunks included.
+    pub total_bytes: u64,
+    /// Bytes of the corrupted chunks kept in `QUARANTINE_DIR`.
+    pub quarantined_bytes: u64,
+    /// Bytes available on the disk holding `chunks_dir`, if known.
+    pub free_disk: Option<u64>,
+    /// Configuration of the server, without its signing key.
+    pub config: ServerConfig,
+    /// Public key signing the queries of the server.
+    pub identity: Option<String>,
+    /// Most recent last, at most `MAX_RECENT_ERRORS`.
+    pub recent_errors: Vec<RecentError>,
+    /// Files the querier is allowed to read.
+    pub files: Vec<FileStatus>,
+}
+
+impl AdminStatus {
+    pub fn serialize(&self) -> CdnResult<String> {
+        serde_json::to_string(self).map_err(|e| {
+            CdnError::Other(format!(
+                "Error serializing server {:?} status {}",
+                self.id, e
+            ))
+        })
+    }
+
+    pub fn deserialize(serialized: &str) -> CdnResult<Self> {
+        serde_json::from_str(serialized).map_err(|e| {
+            CdnError::MalformedMetadata(format!(
+                "Error deserializing server status {:?} {}",
+                serialized, e
+            ))
         })
-    })?;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecentError {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub message: String,
+}
+
+/// Completeness of a file stored by a server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileStatus {
+    pub hashed_path: String,
+    /// Unknown until the metadata is received.
+    pub resource_name: Option<String>,
+    pub checksum: Option<String>,
+    pub complete: bool,
+    /// Metadata retrieved by the pull-through cache, the chunks are only
+    /// fetched on demand.
+    #[serde(default)]
+    pub cached: bool,
+    /// Chunks of the file, unknown until the metadata is received.
+    pub chunks: Option<usize>,
+    pub stored_chunks: usize,
+    /// Chunks found corrupted by the scrubbing and not stored again.
+    pub quarantined_chunks: Vec<usize>,
+    /// Uploads of other generations in progress.
+    pub uploads: usize,
+    pub size: u64,
+}
+
+/// Server-wide retention rules, applied by the sweeper on top of the
+/// per-file `ttl`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionConfig {
+    /// Maximum age of a stored file in seconds.
+    pub max_age: Option<u64>,
+    /// Maximum number of bytes kept in `chunks_dir`, oldest files go first.
+    pub max_total_bytes: Option<u64>,
+    /// Seconds between two sweeps.
+    #[serde(default = "default_sweep_interval")]
+    pub sweep_interval: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_total_bytes: None,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+        }
+    }
+}
+
+/// Garbage collection of the uploads that never completed: uploads staged in
+/// `STAGING_DIR`, or stored chunks without metadata, untouched for
+/// `grace_period`. Corrupted chunks are purged from `QUARANTINE_DIR` after
+/// `quarantine_max_age`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GcConfig {
+    /// Seconds without any change before a partial upload is abandoned.
+    #[serde(default = "default_gc_grace_period")]
+    pub grace_period: u64,
+    /// Seconds between two collections.
+    #[serde(default = "default_gc_interval")]
+    pub interval: u64,
+    /// Seconds a corrupted chunk is kept in `QUARANTINE_DIR`.
+    #[serde(default = "default_quarantine_max_age")]
+    pub quarantine_max_age: u64,
+    /// Only reports the partial uploads and quarantined chunks that would be
+    /// removed.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: DEFAULT_GC_GRACE_PERIOD,
+            interval: DEFAULT_GC_INTERVAL,
+            quarantine_max_age: DEFAULT_QUARANTINE_MAX_AGE,
+            dry_run: false,
+        }
+    }
+}
+
+/// Partial upload found by the garbage collection.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartialUpload {
+    /// Directory of the upload in `chunks_dir`.
+    pub hashed_path: String,
+    /// Unknown when the metadata never arrived.
+    pub resource_name: Option<String>,
+    /// Generation being uploaded, `None` for chunks left by an older version
+    /// outside `STAGING_DIR`.
+    pub checksum: Option<String>,
+    /// Chunks owned by the server that are not stored.
+    pub missing_chunks: Vec<usize>,
+    pub size: u64,
+    /// Seconds since the last change.
+    pub idle: u64,
+}
+
+/// Outcome of a garbage collection, nothing is removed in a dry run.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub uploads: Vec<PartialUpload>,
+    /// Number of quarantined chunks purged.
+    pub quarantined_chunks: usize,
+    pub bytes: u64,
+}
+
+/// Periodic verification of the stored chunks against the digests of their
+/// metadata. Corrupted chunks are moved to `QUARANTINE_DIR` in `chunks_dir`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScrubConfig {
+    /// Bytes per second of chunks read.
+    #[serde(default = "default_scrub_rate")]
+    pub rate: u64,
+    /// Seconds before the first pass and between the end of a pass and the
+    /// start of the next.
+    #[serde(default = "default_scrub_interval")]
+    pub interval: u64,
+    /// Retrieves the corrupted chunks from the other servers.
+    #[serde(default)]
+    pub refetch: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            rate: DEFAULT_SCRUB_RATE,
+            interval: DEFAULT_SCRUB_INTERVAL,
+            refetch: false,
+        }
+    }
+}
+
+fn default_scrub_rate() -> u64 {
+    DEFAULT_SCRUB_RATE
+}
+
+fn default_scrub_interval() -> u64 {
+    DEFAULT_SCRUB_INTERVAL
+}
+
+fn default_gc_grace_period() -> u64 {
+    DEFAULT_GC_GRACE_PERIOD
+}
+
+fn default_max_backoff() -> u64 {
+    DEFAULT_MAX_BACKOFF
+}
+
+fn default_gc_interval() -> u64 {
+    DEFAULT_GC_INTERVAL
+}
+
+fn default_quarantine_max_age() -> u64 {
+    DEFAULT_QUARANTINE_MAX_AGE
+}
+
+fn default_sweep_interval() -> u64 {
+    DEFAULT_SWEEP_INTERVAL
+}
+
+fn default_heartbeat_interval() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL
+}
+
+fn default_max_chunk_size() -> usize {
+    DEFAULT_MAX_CHUNK_SIZE
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub policy: RetryPolicy,
+    /// Bytes per second shared by all the transfers of the client.
+    pub rate_limit: Option<u64>,
+    /// Hex encoded public keys of the publishers whose files are accepted,
+    /// unsigned files are accepted when empty.
+    #[serde(default)]
+    pub trusted_publishers: Vec<String>,
+}
+
+/// Retries applied by the client to every chunk put and get.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// Milliseconds before a single request is abandoned.
+    pub request_timeout: u64,
+    pub max_retries: usize,
+    /// Milliseconds waited before the first retry, doubled at every retry.
+    pub backoff: u64,
+    /// Milliseconds the backoff is capped to.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    /// Milliseconds allowed for a whole upload or download.
+    pub deadline: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            deadline: None,
+        }
+    }
+}
+
+/// Builds a selector for `path` carrying the given query properties.
+pub fn selector_with_properties(path: &str, properties: &[(&str, &str)]) -> String {
+    if properties.is_empty() {
+        return path.to_string();
+    }
+    let properties = properties
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(";");
+    format!("{}?({})", path, properties)
+}
+
+pub fn extract_file_path(prefix: &str, path: &str) -> CdnResult<ResourceName> {
+    log::trace!("extract_file_path({:?},{:?}", prefix, path);
+    let p = path
+        .strip_prefix(prefix)
+        .ok_or_else(|| CdnError::Other("Unable to get resource name".to_string()))?;
     let mut v = p.split('/').collect::<Vec<&str>>();
     v.pop();
-    Ok(v.join("/"))
+    ResourceName::new(&v.join("/"))
 }
 
-pub fn extract_complete_file_path(prefix: &str, path: &str) -> ZResult<String> {
+pub fn extract_complete_file_path(prefix: &str, path: &str) -> CdnResult<ResourceName> {
     log::trace!("extract_complete_file_path({:?},{:?}", prefix, path);
-    let p = path.strip_prefix(prefix).ok_or_else(|| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: "Unable to get resource name".to_string()
-        })
-    })?;
-    Ok(p.to_string())
+    let p = path
+        .strip_prefix(prefix)
+        .ok_or_else(|| CdnError::Other("Unable to get resource name".to_string()))?;
+    ResourceName::new(p)
 }
 
-pub fn extract_chunk_number(path: &str) -> ZResult<usize> {
+pub fn extract_chunk_number(path: &str) -> CdnResult<usize> {
     let mut v = path.split('/').collect::<Vec<&str>>();
     v.pop()
-        .ok_or_else(|| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: "Unable to get chunk_number".to_string()
-            })
-        })?
+        .ok_or_else(|| CdnError::Other("Unable to get chunk_number".to_string()))?
         .parse::<usize>()
-        .map_err(|e| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: format!("Unable to parse chunk_number {:?}", e)
-            })
+        .map_err(|e| CdnError::Other(format!("Unable to parse chunk_number {:?}", e)))
+}
+
+/// Returns the `factor` members responsible for `key`, using rendezvous
+/// (highest random weight) hashing.
+pub fn rendezvous_owners<'a>(key: &str, members: &'a [String], factor: usize) -> Vec<&'a String> {
+    let mut scored = members
+        .iter()
+        .map(|member| {
+            (
+                hash_path(&format!("{}{}{}", member, SEPARATOR, key)),
+                member,
+            )
         })
+        .collect::<Vec<(String, &String)>>();
+    scored.sort_by(|a, b| b.0.cmp(&a.0));
+    scored
+        .into_iter()
+        .take(factor)
+        .map(|(_, member)| member)
+        .collect()
+}
+
+/// SHA-256 of a chunk, as recorded in `FileMetadata::digests`.
+pub fn hash_chunk(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:X}", hasher.finalize())
 }
 
 pub fn hash_path(path: &str) -> String {
@@ -170,3 +1036,423 @@ pub fn hash_path(path: &str) -> String {
     let x = hasher.finalize();
     format!("{:X}", x)
 }
+
+/// Whether `name` is a directory name produced by `hash_path`.
+pub fn is_hashed_path(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const PREFIX: &str = "/zenohcdn/files";
+    const ITERATIONS: usize = 10_000;
+
+    /// Characters favouring the separators, dots and the forbidden ones.
+    const ALPHABET: &[char] = &[
+        '/', '/', '/', '.', '.', 'a', 'b', 'Z', '0', '9', '-', '_', ' ', '*', '?', '#', '$', '\0',
+        '\n', '\t', '\u{7f}', '\u{85}', 'é', '日', '=', ';', '(', ')',
+    ];
+
+    fn random_string(rng: &mut StdRng, max_len: usize) -> String {
+        let len = rng.gen_range(0..=max_len);
+        (0..len)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())])
+            .collect()
+    }
+
+    fn random_segment(rng: &mut StdRng) -> String {
+        let len = rng.gen_range(1..=12);
+        (0..len)
+            .map(|_| match rng.gen_range(0..4) {
+                0 => '.',
+                1 => '-',
+                _ => rng.gen_range(b'a'..=b'z') as char,
+            })
+            .collect::<String>()
+    }
+
+    /// A valid resource name, retried until its segments are not `.` or `..`.
+    fn random_name(rng: &mut StdRng) -> String {
+        loop {
+            let segments = (0..rng.gen_range(1..=6))
+                .map(|_| random_segment(rng))
+                .collect::<Vec<String>>();
+            if segments.iter().all(|s| s != "." && s != "..") {
+                return format!("/{}", segments.join("/"));
+            }
+        }
+    }
+
+    /// Checks the invariants documented on `ResourceName`.
+    fn assert_valid(name: &ResourceName) {
+        let name = name.as_str();
+        assert!(name.len() <= MAX_RESOURCE_NAME_LEN, "{:?}", name);
+        assert!(name.starts_with('/'), "{:?}", name);
+        assert!(
+            !name
+                .chars()
+                .any(|c| c.is_control() || matches!(c, '*' | '?' | '#' | '$')),
+            "{:?}",
+            name
+        );
+        for segment in name[1..].split('/') {
+            assert!(!matches!(segment, "" | "." | ".."), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn random_strings_are_valid_names_or_refused() {
+        let mut rng = StdRng::seed_from_u64(44);
+        for _ in 0..ITERATIONS {
+            let name = random_string(&mut rng, 40);
+            if let Ok(resource_name) = ResourceName::new(&name) {
+                assert_valid(&resource_name);
+                assert_eq!(resource_name.as_str(), name);
+            }
+            let path = format!("{}{}", PREFIX, name);
+            if let Ok(resource_name) = extract_complete_file_path(PREFIX, &path) {
+                assert_valid(&resource_name);
+            }
+            if let Ok(resource_name) = extract_file_path(PREFIX, &path) {
+                assert_valid(&resource_name);
+            }
+        }
+    }
+
+    #[test]
+    fn valid_names_round_trip() {
+        let mut rng = StdRng::seed_from_u64(44);
+        for _ in 0..ITERATIONS {
+            let name = random_name(&mut rng);
+            let resource_name = ResourceName::new(&name).unwrap();
+            assert_valid(&resource_name);
+
+            let path = format!("{}{}", PREFIX, name);
+            assert_eq!(
+                extract_complete_file_path(PREFIX, &path).unwrap().as_str(),
+                name
+            );
+
+            let chunk_number = rng.gen_range(0..100_000);
+            let chunk_path = format!("{}/{}", path, chunk_number);
+            assert_eq!(
+                extract_file_path(PREFIX, &chunk_path).unwrap().as_str(),
+                name
+            );
+            assert_eq!(extract_chunk_number(&chunk_path).unwrap(), chunk_number);
+        }
+    }
+
+    #[test]
+    fn random_segments_are_refused_when_invalid() {
+        let mut rng = StdRng::seed_from_u64(44);
+        let invalid = [
+            "", ".", "..", "a*", "*", "**", "a?b", "#", "$x", "a\0", "\n",
+        ];
+        for _ in 0..ITERATIONS {
+            let mut segments = (0..rng.gen_range(1..=4))
+                .map(|_| random_segment(&mut rng))
+                .collect::<Vec<String>>();
+            let position = rng.gen_range(0..=segments.len());
+            let segment = invalid[rng.gen_range(0..invalid.len())];
+            segments.insert(position, segment.to_string());
+            let name = format!("/{}", segments.join("/"));
+            assert!(ResourceName::new(&name).is_err(), "{:?}", name);
+            let path = format!("{}{}", PREFIX, name);
+            assert!(
+                extract_complete_file_path(PREFIX, &path).is_err(),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn dot_segments_are_refused() {
+        for name in &[
+            "/..",
+            "/.",
+            "/../etc/passwd",
+            "/a/../../b",
+            "/a/./b",
+            "/a/..",
+            "/a/b/.",
+        ] {
+            assert!(ResourceName::new(name).is_err(), "{:?}", name);
+            let path = format!("{}{}", PREFIX, name);
+            assert!(
+                extract_complete_file_path(PREFIX, &path).is_err(),
+                "{:?}",
+                path
+            );
+            assert!(extract_file_path(PREFIX, &format!("{}/0", path)).is_err());
+        }
+        for name in &["/...", "/a/.b", "/a/b..", "/.hidden"] {
+            assert!(ResourceName::new(name).is_ok(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn empty_segments_are_refused() {
+        for name in &["", "/", "//", "/a//b", "/a/", "//a", "/a/b/"] {
+            assert!(ResourceName::new(name).is_err(), "{:?}", name);
+        }
+        // The chunk number cannot be dropped to make an empty segment valid.
+        assert!(extract_file_path(PREFIX, &format!("{}/0", PREFIX)).is_err());
+        assert!(extract_file_path(PREFIX, &format!("{}/a//0", PREFIX)).is_err());
+    }
+
+    #[test]
+    fn control_characters_and_wildcards_are_refused() {
+        let forbidden = (0u32..0x20)
+            .chain(0x7f..0xa0)
+            .filter_map(std::char::from_u32)
+            .chain(vec!['*', '?', '#', '$']);
+        for c in forbidden {
+            for name in &[format!("/{}", c), format!("/a{}b", c), format!("/a/b{}", c)] {
+                assert!(ResourceName::new(name).is_err(), "{:?}", name);
+                let path = format!("{}{}", PREFIX, name);
+                assert!(extract_complete_file_path(PREFIX, &path).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn overlong_names_are_refused() {
+        let longest = format!("/{}", "a".repeat(MAX_RESOURCE_NAME_LEN - 1));
+        assert!(ResourceName::new(&longest).is_ok());
+        let overlong = format!("{}a", longest);
+        assert!(ResourceName::new(&overlong).is_err());
+        assert!(extract_complete_file_path(PREFIX, &format!("{}{}", PREFIX, overlong)).is_err());
+
+        // Multi-byte characters count in bytes.
+        let overlong = format!("/{}", "é".repeat(MAX_RESOURCE_NAME_LEN / 2));
+        assert!(ResourceName::new(&overlong).is_err());
+    }
+
+    #[test]
+    fn prefix_confusion_is_refused() {
+        for path in &[
+            "/zenohcdn/filesX/a",
+            "/zenohcdn/files.bak/a",
+            "/zenohcdn/files",
+            "/zenohcdn/file/a",
+            "/zenohcdnX/files/a",
+            "zenohcdn/files/a",
+            "/other/zenohcdn/files/a",
+        ] {
+            assert!(
+                extract_complete_file_path(PREFIX, path).is_err(),
+                "{:?}",
+                path
+            );
+            assert!(extract_file_path(PREFIX, &format!("{}/0", path)).is_err());
+        }
+        assert_eq!(
+            extract_complete_file_path(PREFIX, "/zenohcdn/files/a")
+                .unwrap()
+                .as_str(),
+            "/a"
+        );
+    }
+
+    #[test]
+    fn chunk_numbers_are_parsed_strictly() {
+        for path in &["/a/b", "/a/-1", "/a/1.5", "/a/ 1", "/a/", "", "/a/1a"] {
+            assert!(extract_chunk_number(path).is_err(), "{:?}", path);
+        }
+        assert_eq!(extract_chunk_number("/a/b/42").unwrap(), 42);
+    }
+
+    fn metadata(size: u64, chunk_size: usize, chunks: usize) -> FileMetadata {
+        FileMetadata {
+            filename: "b".to_string(),
+            checksum: "00".to_string(),
+            chunk_size,
+            chunks,
+            resource_name: "/a/b".to_string(),
+            size,
+            ttl: None,
+            generation: 1,
+            compression: Compression::None,
+            encryption: None,
+            digests: Vec::new(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn ttl_is_counted_from_the_generation() {
+        let mut metadata = metadata(0, 1024, 1);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        metadata.generation = 1_000_000;
+        assert_eq!(metadata.age(start + Duration::from_secs(5)), Some(5));
+        assert!(!metadata.expired(start + Duration::from_secs(3_600)));
+
+        metadata.ttl = Some(60);
+        assert!(!metadata.expired(start));
+        assert!(!metadata.expired(start + Duration::from_secs(59)));
+        assert!(metadata.expired(start + Duration::from_secs(60)));
+
+        metadata.generation = 0;
+        assert_eq!(metadata.age(start), None);
+        assert!(!metadata.expired(start + Duration::from_secs(3_600)));
+    }
+
+    #[test]
+    fn inconsistent_layouts_are_refused() {
+        for (size, chunk_size, chunks) in &[
+            (1500, 1024, 2),
+            (2048, 1024, 2),
+            (2048, 1024, 3),
+            (0, 1024, 1),
+        ] {
+            assert!(metadata(*size, *chunk_size, *chunks).check_layout().is_ok());
+        }
+        for (size, chunk_size, chunks) in &[
+            (1500, 1024, 1),
+            (1500, 1024, 3),
+            (1500, 0, 2),
+            (0, 1024, 0),
+            (u64::MAX, usize::MAX, usize::MAX),
+        ] {
+            let metadata = metadata(*size, *chunk_size, *chunks);
+            assert!(metadata.check_layout().is_err(), "{:?}", metadata);
+        }
+
+        let mut metadata = metadata(1500, 1024, 2);
+        metadata.digests = vec![hash_chunk(b"a")];
+        assert!(metadata.check_layout().is_err());
+    }
+
+    #[test]
+    fn chunks_are_validated_by_length_and_digest() {
+        let mut metadata = metadata(1500, 1024, 2);
+        assert!(metadata.validate_chunk(0, &[0; 1024]).is_ok());
+        assert!(metadata.validate_chunk(1, &[0; 476]).is_ok());
+        assert!(metadata.validate_chunk(1, &[0; 1024]).is_err());
+        assert!(metadata.validate_chunk(0, &[0; 1023]).is_err());
+        assert!(metadata.validate_chunk(2, &[0; 476]).is_err());
+
+        metadata.digests = vec![hash_chunk(&[0; 1024]), hash_chunk(&[1; 476])];
+        assert!(metadata.validate_chunk(0, &[0; 1024]).is_ok());
+        assert!(metadata.validate_chunk(1, &[1; 476]).is_ok());
+        assert!(metadata.validate_chunk(1, &[0; 476]).is_err());
+        // A matching digest does not skip the length check.
+        metadata.digests[1] = hash_chunk(&[1; 477]);
+        assert!(metadata.validate_chunk(1, &[1; 477]).is_err());
+    }
+
+    #[test]
+    fn zstd_frames_expanding_past_the_bound_are_refused() {
+        let frame = Compression::Zstd.compress(&[0; 4096]).unwrap();
+        assert!(frame.len() < 4096);
+        assert_eq!(
+            Compression::Zstd.decompress(&frame, 4096).unwrap().len(),
+            4096
+        );
+        assert!(Compression::Zstd.decompress(&frame, 4095).is_err());
+    }
+
+    #[test]
+    fn lz4_frames_expanding_past_the_bound_are_refused() {
+        let frame = Compression::Lz4.compress(&[0; 4096]).unwrap();
+        assert_eq!(
+            Compression::Lz4.decompress(&frame, 4096).unwrap().len(),
+            4096
+        );
+        assert!(Compression::Lz4.decompress(&frame, 4095).is_err());
+
+        // The prepended size is checked before anything is allocated.
+        let mut lying = frame;
+        lying[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Compression::Lz4.decompress(&lying, 4096).is_err());
+        assert!(Compression::Lz4.decompress(&[0; 3], 4096).is_err());
+    }
+
+    #[test]
+    fn encoded_chunks_are_bounded() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let random = (0..4096).map(|_| rng.gen()).collect::<Vec<u8>>();
+        for compression in &[Compression::Zstd, Compression::Lz4] {
+            let mut metadata = metadata(4096, 4096, 1);
+            metadata.compression = *compression;
+            let compressed = compression.compress(&random).unwrap();
+            assert!(metadata.validate_chunk(0, &compressed).is_ok());
+            let bound = compression.max_compressed_len(4096);
+            assert!(metadata.validate_chunk(0, &vec![0; bound + 1]).is_err());
+
+            metadata.encryption = Some(Encryption {
+                key_id: "key".to_string(),
+                nonce: "00".repeat(8),
+            });
+            assert!(metadata
+                .validate_chunk(0, &vec![0; bound + ENCRYPTION_TAG_LEN])
+                .is_ok());
+            assert!(metadata
+                .validate_chunk(0, &vec![0; bound + ENCRYPTION_TAG_LEN + 1])
+                .is_err());
+        }
+    }
+
+    fn rule(prefix: &str) -> AclRule {
+        AclRule {
+            prefix: prefix.to_string(),
+            upload: Vec::new(),
+            read: vec![prefix.to_string()],
+            delete: false,
+        }
+    }
+
+    #[test]
+    fn acl_prefixes_match_whole_segments() {
+        assert!(rule("/a").matches("/a"));
+        assert!(rule("/a").matches("/a/b"));
+        assert!(!rule("/a").matches("/ab"));
+        assert!(rule("/a/").matches("/a/b"));
+        assert!(!rule("/a/b").matches("/a"));
+    }
+
+    #[test]
+    fn longest_acl_prefix_applies() {
+        let acl = vec![rule("/"), rule("/a/b"), rule("/a")];
+        let prefix = |name| acl_rule(&acl, name).map(|rule| rule.prefix.as_str());
+        assert_eq!(prefix("/a/b/c"), Some("/a/b"));
+        assert_eq!(prefix("/a/bc"), Some("/a"));
+        assert_eq!(prefix("/c"), Some("/"));
+        assert!(acl_rule(&acl[1..], "/c").is_none());
+    }
+
+    #[test]
+    fn rendezvous_owners_are_stable() {
+        let members = (0..8).map(|i| format!("server{}", i)).collect::<Vec<_>>();
+        for i in 0..100 {
+            let key = format!("/a/b/{}", i);
+            let owners = rendezvous_owners(&key, &members, 3);
+            assert_eq!(owners.len(), 3);
+
+            // The ranking does not depend on the order of the members.
+            let mut reversed = members.clone();
+            reversed.reverse();
+            assert_eq!(rendezvous_owners(&key, &reversed, 3), owners);
+
+            // A larger factor only adds owners.
+            assert_eq!(&rendezvous_owners(&key, &members, 5)[..3], &owners[..]);
+
+            // Losing a member that does not own the key moves nothing.
+            let remaining = members
+                .iter()
+                .filter(|member| !owners.contains(member))
+                .skip(1)
+                .chain(owners.iter().cloned())
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(rendezvous_owners(&key, &remaining, 3), owners);
+        }
+        assert_eq!(rendezvous_owners("/a", &members[..2], 3).len(), 2);
+    }
+}
@@ -12,35 +12,32 @@
 //   ADLINK zenoh team, <zenoh@adlink-labs.tech>
 //
 
+use crate::error::{CdnError, CdnResult};
 use async_std::fs;
 use async_std::fs::File;
 use async_std::prelude::*;
 use async_std::{fs::OpenOptions, io::SeekFrom};
 use memmap2::MmapOptions;
 use std::path::Path;
-use zenoh::{ZError, ZErrorKind, ZResult};
+use std::time::{Duration, Instant, SystemTime};
 
 pub async fn get_bytes_from_file(
     filename: &Path,
     chunk_number: usize,
     chunk_size: usize,
-) -> ZResult<Vec<u8>> {
+) -> CdnResult<Vec<u8>> {
     log::trace!(
         "Getting the file {:?}, chunk number {}.",
         filename,
         chunk_number
     );
-    let mut f = File::open(&filename).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("File not found {:?} {:?}", filename, e)
-        })
-    })?;
+    let mut f = File::open(&filename)
+        .await
+        .map_err(|e| CdnError::Io(format!("File not found {:?} {:?}", filename, e)))?;
 
-    let metadata = fs::metadata(&filename).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to get metadata for {:?} {:?}", filename, e)
-        })
-    })?;
+    let metadata = fs::metadata(&filename)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to get metadata for {:?} {:?}", filename, e)))?;
     let file_size = metadata.len() as usize;
 
     let offset: usize = chunk_number * chunk_size;
@@ -60,69 +57,63 @@ pub async fn get_bytes_from_file(
         buffer_len
     );
     let mut buffer = vec![0; buffer_len];
-    f.read_exact(&mut buffer).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to read from file {:?} {:?}", filename, e)
-        })
-    })?;
+    f.read_exact(&mut buffer)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to read from file {:?} {:?}", filename, e)))?;
     Ok(buffer)
 }
 
-pub async fn create_dir_if_not_exists(dir: &Path) -> ZResult<()> {
+pub async fn create_dir_if_not_exists(dir: &Path) -> CdnResult<()> {
     match async_std::fs::create_dir_all(dir).await {
         Ok(()) => Ok(()),
         Err(e) => match e.kind() {
             async_std::io::ErrorKind::AlreadyExists => Ok(()),
-            _ => zenoh_util::zerror!(ZErrorKind::Other {
-                descr: format!("Error when creating folder {:?} {:?}", dir, e)
-            }),
+            _ => Err(CdnError::Io(format!(
+                "Error when creating folder {:?} {:?}",
+                dir, e
+            ))),
         },
     }
 }
 
-pub async fn write_chunk_file(filename: &Path, content: &[u8]) -> ZResult<()> {
-    let mut file = async_std::fs::File::create(filename).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Error when creating file {:?} {:?}", filename, e)
-        })
-    })?;
-    Ok(file.write_all(content).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Error when writing bytes to file {:?} {:?}", filename, e)
-        })
-    })?)
+pub async fn write_chunk_file(filename: &Path, content: &[u8]) -> CdnResult<()> {
+    let mut file = async_std::fs::File::create(filename)
+        .await
+        .map_err(|e| CdnError::Io(format!("Error when creating file {:?} {:?}", filename, e)))?;
+    file.write_all(content).await.map_err(|e| {
+        CdnError::Io(format!(
+            "Error when writing bytes to file {:?} {:?}",
+            filename, e
+        ))
+    })
 }
 
-pub async fn write_metadata_file(filename: &Path, metadata: &str) -> ZResult<()> {
-    let mut file = async_std::fs::File::create(filename).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Error when creating file {:?} {:?}", filename, e)
-        })
-    })?;
-    Ok(file.write_all(metadata.as_bytes()).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Error when writing bytes to file {:?} {:?}", filename, e)
-        })
-    })?)
+pub async fn write_metadata_file(filename: &Path, metadata: &str) -> CdnResult<()> {
+    let mut file = async_std::fs::File::create(filename)
+        .await
+        .map_err(|e| CdnError::Io(format!("Error when creating file {:?} {:?}", filename, e)))?;
+    file.write_all(metadata.as_bytes()).await.map_err(|e| {
+        CdnError::Io(format!(
+            "Error when writing bytes to file {:?} {:?}",
+            filename, e
+        ))
+    })
 }
 
-pub async fn create_destination_file(filename: &Path, size: u64) -> ZResult<File> {
+pub async fn create_destination_file(filename: &Path, size: u64) -> CdnResult<File> {
     let f = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .open(filename)
         .await
-        .map_err(|e| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: format!("Unable to create file {:?} {:?}", filename, e)
-            })
-        })?;
+        .map_err(|e| CdnError::Io(format!("Unable to create file {:?} {:?}", filename, e)))?;
 
     f.set_len(size).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to allocate space in file {:?} {:?}", filename, e)
-        })
+        CdnError::Io(format!(
+            "Unable to allocate space in file {:?} {:?}",
+            filename, e
+        ))
     })?;
 
     Ok(f)
@@ -133,16 +124,26 @@ pub async fn write_destination_file(
     src: &[u8],
     chunk_num: usize,
     chunk_size: usize,
-) -> ZResult<()> {
+) -> CdnResult<()> {
     let mut data = unsafe {
-        MmapOptions::new().map_mut(f).map_err(|e| {
-            zenoh_util::zerror2!(ZErrorKind::Other {
-                descr: format!("Unable to access file {:?} {:?}", f, e)
-            })
-        })?
+        MmapOptions::new()
+            .map_mut(f)
+            .map_err(|e| CdnError::Io(format!("Unable to access file {:?} {:?}", f, e)))?
+    };
+    let initial_position = chunk_num.checked_mul(chunk_size);
+    let final_position = initial_position.and_then(|p| p.checked_add(src.len()));
+    let (initial_position, final_position) = match (initial_position, final_position) {
+        (Some(initial), Some(last)) if last <= data.len() => (initial, last),
+        _ => {
+            return Err(CdnError::Integrity(format!(
+                "Chunk {} of {} bytes does not fit in file {:?} of {} bytes",
+                chunk_num,
+                src.len(),
+                f,
+                data.len()
+            )))
+        }
     };
-    let initial_position: usize = chunk_num * chunk_size;
-    let final_position: usize = initial_position + src.len();
     log::trace!(
         "Write from position {} to position {}.",
         initial_position,
@@ -152,34 +153,180 @@ pub async fn write_destination_file(
     Ok(())
 }
 
-pub async fn read_file_to_string(path: &Path) -> ZResult<String> {
-    Ok(async_std::fs::read_to_string(path).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to read file {:?} {:?}", path, e)
-        })
-    })?)
+fn read_error(path: &Path, e: std::io::Error) -> CdnError {
+    match e.kind() {
+        async_std::io::ErrorKind::NotFound => CdnError::NotFound(format!("File {:?}", path)),
+        _ => CdnError::Io(format!("Unable to read file {:?} {:?}", path, e)),
+    }
 }
 
-pub async fn read_file_to_vec(path: &Path) -> ZResult<Vec<u8>> {
-    let mut f = File::open(&path).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to open file {:?} {:?}", path, e)
-        })
-    })?;
-    let metadata = fs::metadata(&path).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!("Unable to read file metadata {:?} {:?}", path, e)
-        })
-    })?;
+pub async fn read_file_to_string(path: &Path) -> CdnResult<String> {
+    async_std::fs::read_to_string(path)
+        .await
+        .map_err(|e| read_error(path, e))
+}
+
+pub async fn read_file_to_vec(path: &Path) -> CdnResult<Vec<u8>> {
+    let mut f = File::open(&path).await.map_err(|e| read_error(path, e))?;
+    let metadata = fs::metadata(&path)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to read file metadata {:?} {:?}", path, e)))?;
     let mut buffer = vec![0; metadata.len() as usize];
     f.read(&mut buffer).await.map_err(|e| {
-        zenoh_util::zerror2!(ZErrorKind::Other {
-            descr: format!(
-                "Buffer overflow when reading data from file {:?} {:?}",
-                path, e
-            )
-        })
+        CdnError::Io(format!(
+            "Buffer overflow when reading data from file {:?} {:?}",
+            path, e
+        ))
     })?;
 
     Ok(buffer)
 }
+
+pub async fn remove_file_if_exists(path: &Path) -> CdnResult<()> {
+    match async_std::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) => match e.kind() {
+            async_std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(CdnError::Io(format!(
+                "Unable to remove file {:?} {:?}",
+                path, e
+            ))),
+        },
+    }
+}
+
+pub async fn rename_file(from: &Path, to: &Path) -> CdnResult<()> {
+    async_std::fs::rename(from, to)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to rename {:?} to {:?} {:?}", from, to, e)))
+}
+
+pub async fn path_exists(path: &Path) -> bool {
+    async_std::fs::metadata(path).await.is_ok()
+}
+
+pub async fn remove_dir_if_exists(dir: &Path) -> CdnResult<()> {
+    match async_std::fs::remove_dir_all(dir).await {
+        Ok(()) => Ok(()),
+        Err(e) => match e.kind() {
+            async_std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(CdnError::Io(format!(
+                "Unable to remove folder {:?} {:?}",
+                dir, e
+            ))),
+        },
+    }
+}
+
+pub async fn list_dirs(dir: &Path) -> CdnResult<Vec<std::path::PathBuf>> {
+    let mut entries = async_std::fs::read_dir(dir)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to read folder {:?} {:?}", dir, e)))?;
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry.map_err(|e| {
+            CdnError::Io(format!("Unable to read folder entry in {:?} {:?}", dir, e))
+        })?;
+        if let Ok(file_type) = entry.file_type().await {
+            if file_type.is_dir() {
+                dirs.push(entry.path().into());
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+pub async fn list_files(dir: &Path) -> CdnResult<Vec<std::path::PathBuf>> {
+    let mut entries = async_std::fs::read_dir(dir)
+        .await
+        .map_err(|e| CdnError::Io(format!("Unable to read folder {:?} {:?}", dir, e)))?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry.map_err(|e| {
+            CdnError::Io(format!("Unable to read folder entry in {:?} {:?}", dir, e))
+        })?;
+        if let Ok(file_type) = entry.file_type().await {
+            if file_type.is_file() {
+                files.push(entry.path().into());
+            }
+        }
+    }
+    Ok(files)
+}
+
+pub async fn modified_time(path: &Path) -> CdnResult<SystemTime> {
+    fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .map_err(|e| {
+            CdnError::Io(format!(
+                "Unable to get modification time of {:?} {:?}",
+                path, e
+            ))
+        })
+}
+
+/// Token bucket limiting a flow to `rate` bytes per second, allowing bursts
+/// of up to one second of traffic.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    /// Bytes that can be sent right away, negative when the flow is in debt,
+    /// and when they were last refilled.
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            state: std::sync::Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Waits until `bytes` can be sent without exceeding the rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            match *tokens < 0.0 {
+                true => Some(Duration::from_secs_f64(-*tokens / rate)),
+                false => None,
+            }
+        };
+        if let Some(wait) = wait {
+            async_std::task::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_allows_bursts_then_waits() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        async_std::task::block_on(limiter.acquire(10_000));
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        // The bucket is empty, half a second of traffic is owed.
+        async_std::task::block_on(limiter.acquire(5_000));
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn rate_limiter_never_accumulates_more_than_a_second() {
+        let limiter = RateLimiter::new(1_000_000);
+        std::thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        async_std::task::block_on(limiter.acquire(1_200_000));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}